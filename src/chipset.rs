@@ -0,0 +1,233 @@
+//! Timing profiles for the LED chipsets the output program can drive
+//!
+//! All of these use the same one wire protocol, where every bit starts high
+//! and the length of the high part says whether it's a 0 or a 1. They only
+//! differ in how long each part is, which is what lets one PIO program drive
+//! all of them.
+
+use crate::clock_divisor::ClockDivisor;
+
+/// The longest a single instruction can take, including its delay
+///
+/// With one side-set bit there are 4 bits of delay left.
+const MAX_SEGMENT_CYCLES: u32 = 16;
+
+/// The most PIO cycles worth spending on one bit
+const MAX_CYCLES_PER_BIT: u32 = MAX_SEGMENT_CYCLES * 3;
+
+/// Bit timing of a chipset
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Timing {
+    /// How long a 0 bit stays high, in nanoseconds
+    pub t0h_ns: u32,
+    /// How long a 1 bit stays high, in nanoseconds
+    pub t1h_ns: u32,
+    /// Bits per second on the data line, usually 800kHz or 400kHz
+    pub bit_rate: u32,
+    /// How long the line must be held low to latch a frame, in microseconds
+    pub reset_us: u32,
+}
+
+/// Timing turned into PIO cycles for a particular system clock
+///
+/// A 0 bit is high for `t1` cycles then low for `t2 + t3`. A 1 bit is high for
+/// `t1 + t2` cycles then low for `t3`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ProgramTiming {
+    t1: u8,
+    t2: u8,
+    t3: u8,
+    clock_divisor: ClockDivisor,
+}
+
+impl ProgramTiming {
+    /// Returns `None` unless every segment is between 1 and 16 cycles, which
+    /// is all a single instruction and its delay can take
+    pub fn new(t1: u8, t2: u8, t3: u8, clock_divisor: ClockDivisor) -> Option<ProgramTiming> {
+        if [t1, t2, t3].iter().any(|&t| t == 0 || t as u32 > MAX_SEGMENT_CYCLES) {
+            return None
+        }
+
+        Some(ProgramTiming {t1, t2, t3, clock_divisor})
+    }
+
+    pub fn t1(&self) -> u8 {
+        self.t1
+    }
+
+    pub fn t2(&self) -> u8 {
+        self.t2
+    }
+
+    pub fn t3(&self) -> u8 {
+        self.t3
+    }
+
+    pub fn clock_divisor(&self) -> ClockDivisor {
+        self.clock_divisor
+    }
+
+    /// Number of PIO cycles it takes to send a single bit
+    pub fn cycles_per_bit(&self) -> u32 {
+        self.t1 as u32 + self.t2 as u32 + self.t3 as u32
+    }
+}
+
+impl Timing {
+    /// Length of a single bit, in nanoseconds
+    pub fn period_ns(&self) -> u32 {
+        1_000_000_000 / self.bit_rate
+    }
+
+    /// Works out the program delays and clock divisor for a system clock
+    ///
+    /// This picks the most cycles per bit that still fit in the program's
+    /// delays, which gives the finest control over the pulse widths.
+    ///
+    /// Returns `None` if the system clock is too slow for this bit rate.
+    pub fn program_timing(&self, system_clock_hz: u32) -> Option<ProgramTiming> {
+        let period_ns = self.period_ns();
+
+        for cycles in (3..=MAX_CYCLES_PER_BIT).rev() {
+            let t1 = div_round(self.t0h_ns * cycles, period_ns);
+            let t2 = div_round(self.t1h_ns * cycles, period_ns).saturating_sub(t1);
+            let Some(t3) = cycles.checked_sub(t1 + t2) else {
+                continue
+            };
+
+            if [t1, t2, t3].iter().any(|&t| t > MAX_SEGMENT_CYCLES) {
+                continue
+            }
+
+            let Ok((clock_divisor, _)) = ClockDivisor::from_frequency(cycles * self.bit_rate, system_clock_hz) else {
+                continue
+            };
+
+            if let Some(program) = ProgramTiming::new(t1 as u8, t2 as u8, t3 as u8, clock_divisor) {
+                return Some(program)
+            }
+        }
+
+        None
+    }
+}
+
+/// LED chipsets with known timing
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Chipset {
+    /// WS2811 in low speed mode
+    Ws2811Slow,
+    /// WS2811 in high speed mode
+    Ws2811,
+    #[default]
+    Ws2812b,
+    Ws2813,
+    Ws2815,
+    /// SK6812, both RGB and RGBW
+    Sk6812,
+    Tm1814,
+    Apa106,
+    Ucs1903,
+}
+
+impl Chipset {
+    pub fn timing(&self) -> Timing {
+        match self {
+            Chipset::Ws2811Slow => Timing {t0h_ns: 500, t1h_ns: 1200, bit_rate: 400_000, reset_us: 280},
+            Chipset::Ws2811 => Timing {t0h_ns: 250, t1h_ns: 600, bit_rate: 800_000, reset_us: 280},
+            Chipset::Ws2812b => Timing {t0h_ns: 400, t1h_ns: 800, bit_rate: 800_000, reset_us: 280},
+            Chipset::Ws2813 => Timing {t0h_ns: 375, t1h_ns: 875, bit_rate: 800_000, reset_us: 300},
+            Chipset::Ws2815 => Timing {t0h_ns: 375, t1h_ns: 875, bit_rate: 800_000, reset_us: 280},
+            Chipset::Sk6812 => Timing {t0h_ns: 300, t1h_ns: 600, bit_rate: 800_000, reset_us: 80},
+            Chipset::Tm1814 => Timing {t0h_ns: 360, t1h_ns: 720, bit_rate: 800_000, reset_us: 200},
+            Chipset::Apa106 => Timing {t0h_ns: 350, t1h_ns: 1360, bit_rate: 580_000, reset_us: 50},
+            Chipset::Ucs1903 => Timing {t0h_ns: 500, t1h_ns: 2000, bit_rate: 400_000, reset_us: 24},
+        }
+    }
+}
+
+fn div_round(numerator: u32, denominator: u32) -> u32 {
+    (numerator + denominator / 2) / denominator
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CHIPSETS: [Chipset; 9] = [
+        Chipset::Ws2811Slow,
+        Chipset::Ws2811,
+        Chipset::Ws2812b,
+        Chipset::Ws2813,
+        Chipset::Ws2815,
+        Chipset::Sk6812,
+        Chipset::Tm1814,
+        Chipset::Apa106,
+        Chipset::Ucs1903,
+    ];
+
+    /// Allowed error in a high pulse, as in `waveform::Tolerance`
+    const TOLERANCE_NS: u32 = 150;
+
+    fn check(chipset: Chipset, system_clock_hz: u32) {
+        let timing = chipset.timing();
+        let program = timing
+            .program_timing(system_clock_hz)
+            .unwrap_or_else(|| panic!("{chipset:?} at {system_clock_hz}Hz"));
+
+        for t in [program.t1, program.t2, program.t3] {
+            assert!(t > 0 && t as u32 <= MAX_SEGMENT_CYCLES, "{chipset:?} {program:?}");
+        }
+
+        // Cycle length as it actually comes out of the divisor
+        let cycle_ps = program.clock_divisor.period_ps(system_clock_hz);
+        let ns = |cycles: u8| (cycles as u64 * cycle_ps / 1000) as u32;
+
+        let t0h_ns = ns(program.t1);
+        let t1h_ns = ns(program.t1 + program.t2);
+        let period_ns = ns(program.t1 + program.t2 + program.t3);
+
+        assert!(t0h_ns.abs_diff(timing.t0h_ns) <= TOLERANCE_NS, "{chipset:?} T0H {t0h_ns}ns");
+        assert!(t1h_ns.abs_diff(timing.t1h_ns) <= TOLERANCE_NS, "{chipset:?} T1H {t1h_ns}ns");
+
+        // The bit rate itself should be within 1%
+        assert!(period_ns.abs_diff(timing.period_ns()) * 100 <= timing.period_ns(), "{chipset:?} {period_ns}ns");
+    }
+
+    #[test]
+    fn every_chipset_fits_at_125mhz() {
+        for chipset in CHIPSETS {
+            check(chipset, 125_000_000);
+
+            // There's plenty of clock to use the longest bit possible
+            let program = chipset.timing().program_timing(125_000_000).unwrap();
+            assert!(program.cycles_per_bit() > MAX_SEGMENT_CYCLES);
+        }
+    }
+
+    #[test]
+    fn segments_have_to_fit_an_instruction() {
+        let divisor = ClockDivisor::new(1, 0);
+
+        assert!(ProgramTiming::new(0, 2, 3, divisor).is_none());
+        assert!(ProgramTiming::new(1, 2, 17, divisor).is_none());
+        assert_eq!(ProgramTiming::new(1, 16, 3, divisor).unwrap().cycles_per_bit(), 20);
+    }
+
+    #[test]
+    fn every_chipset_fits_at_12mhz() {
+        for chipset in CHIPSETS {
+            check(chipset, 12_000_000);
+        }
+    }
+
+    #[test]
+    fn too_slow_a_clock_has_no_timing() {
+        for chipset in CHIPSETS {
+            let timing = chipset.timing();
+
+            // Not even three cycles per bit
+            assert_eq!(timing.program_timing(timing.bit_rate * 2), None, "{chipset:?}");
+        }
+    }
+}
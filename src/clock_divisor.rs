@@ -0,0 +1,135 @@
+//! Works out PIO clock divisors from a target frequency
+//!
+//! State machines run at the system clock divided by a 16.8 fixed point
+//! number. Working those out by hand breaks as soon as the system clock
+//! changes, so this does it from whatever the clock actually is. Use
+//! `Hardware::system_clock_hz` for that.
+
+#[derive(Debug)]
+pub enum Error {
+    /// The target is faster than the system clock
+    FrequencyTooHigh,
+    /// The target needs a divisor larger than 65535, or is 0
+    FrequencyTooLow,
+}
+
+/// A fixed point clock divisor with 16 integer bits and 8 fraction bits
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ClockDivisor {
+    pub int: u16,
+    pub frac: u8,
+}
+
+impl ClockDivisor {
+    pub const fn new(int: u16, frac: u8) -> ClockDivisor {
+        ClockDivisor {int, frac}
+    }
+
+    /// Finds the divisor that gets closest to a target frequency
+    ///
+    /// * `target_hz` - What the state machine should run at. For a strip, this
+    ///   is the bit rate times the program's cycles per bit.
+    /// * `system_clock_hz` - What the system clock is actually running at
+    ///
+    /// Returns the divisor along with how far off the frequency it achieves
+    /// is, in parts per million. A positive error means it runs fast.
+    pub fn from_frequency(target_hz: u32, system_clock_hz: u32) -> Result<(ClockDivisor, i32), Error> {
+        if target_hz > system_clock_hz {
+            return Err(Error::FrequencyTooHigh)
+        }
+
+        if target_hz == 0 {
+            return Err(Error::FrequencyTooLow)
+        }
+
+        // Round to the nearest 1/256th
+        let target = target_hz as u64;
+        let fixed = ((system_clock_hz as u64) * 256 + target / 2) / target;
+        let int = fixed >> 8;
+
+        if int > u16::MAX as u64 {
+            return Err(Error::FrequencyTooLow)
+        }
+
+        let divisor = ClockDivisor::new(int as u16, fixed as u8);
+
+        let achieved = divisor.frequency(system_clock_hz) as i64;
+        let error = (achieved - target as i64) * 1_000_000 / target as i64;
+
+        Ok((divisor, error as i32))
+    }
+
+    /// The frequency a state machine runs at with this divisor
+    pub fn frequency(&self, system_clock_hz: u32) -> u32 {
+        ((system_clock_hz as u64) * 256 / self.fixed()) as u32
+    }
+
+    /// How long a single state machine cycle lasts with this divisor, in
+    /// picoseconds
+    pub fn period_ps(&self, system_clock_hz: u32) -> u64 {
+        self.fixed() * 1_000_000_000_000 / (system_clock_hz as u64 * 256)
+    }
+
+    /// The divisor in 1/256ths, as the hardware sees it
+    fn fixed(&self) -> u64 {
+        // The hardware treats an integer part of 0 as 65536
+        let int = match self.int {
+            0 => 65536,
+            int => int as u64,
+        };
+
+        int << 8 | self.frac as u64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SYSTEM_CLOCK_HZ: u32 = 125_000_000;
+
+    #[test]
+    fn exact_divisors_have_no_error() {
+        let (divisor, error) = ClockDivisor::from_frequency(8_000_000, SYSTEM_CLOCK_HZ).unwrap();
+
+        // 125 / 8 = 15.625, which is 15 and 160/256
+        assert_eq!(divisor, ClockDivisor::new(15, 160));
+        assert_eq!(error, 0);
+        assert_eq!(divisor.frequency(SYSTEM_CLOCK_HZ), 8_000_000);
+        assert_eq!(divisor.period_ps(SYSTEM_CLOCK_HZ), 125_000);
+    }
+
+    #[test]
+    fn rounds_to_the_nearest_step() {
+        // 125 / 3 = 41.666..., and 0.666... is closer to 171/256 than 170/256
+        let (divisor, error) = ClockDivisor::from_frequency(3_000_000, SYSTEM_CLOCK_HZ).unwrap();
+        assert_eq!(divisor, ClockDivisor::new(41, 171));
+        assert!(error.abs() < 100, "{error}ppm");
+
+        // A hair off a whole number rounds to it
+        let (divisor, _) = ClockDivisor::from_frequency(3_048_780, SYSTEM_CLOCK_HZ).unwrap();
+        assert_eq!(divisor.int, 41);
+        assert_eq!(divisor.frac, 0);
+    }
+
+    #[test]
+    fn full_speed_divides_by_one() {
+        let (divisor, error) = ClockDivisor::from_frequency(SYSTEM_CLOCK_HZ, SYSTEM_CLOCK_HZ).unwrap();
+
+        assert_eq!(divisor, ClockDivisor::new(1, 0));
+        assert_eq!(error, 0);
+    }
+
+    #[test]
+    fn out_of_range_targets_are_rejected() {
+        assert!(matches!(
+            ClockDivisor::from_frequency(SYSTEM_CLOCK_HZ + 1, SYSTEM_CLOCK_HZ),
+            Err(Error::FrequencyTooHigh),
+        ));
+
+        // The slowest divisor is just under 65536
+        assert!(ClockDivisor::from_frequency(1908, SYSTEM_CLOCK_HZ).is_ok());
+        assert!(matches!(ClockDivisor::from_frequency(1907, SYSTEM_CLOCK_HZ), Err(Error::FrequencyTooLow)));
+        assert!(matches!(ClockDivisor::from_frequency(0, SYSTEM_CLOCK_HZ), Err(Error::FrequencyTooLow)));
+    }
+}
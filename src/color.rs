@@ -0,0 +1,290 @@
+//! Color types and the wire order used to pack them for a strip
+
+/// An 8 bit per channel RGB color
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Rgb8 {
+    pub r: u8,
+    pub g: u8,
+    pub b: u8,
+}
+
+impl Rgb8 {
+    pub const fn new(r: u8, g: u8, b: u8) -> Rgb8 {
+        Rgb8 {r, g, b}
+    }
+}
+
+/// An 8 bit per channel RGB color with a separate white channel
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Rgbw8 {
+    pub r: u8,
+    pub g: u8,
+    pub b: u8,
+    pub w: u8,
+}
+
+impl Rgbw8 {
+    pub const fn new(r: u8, g: u8, b: u8, w: u8) -> Rgbw8 {
+        Rgbw8 {r, g, b, w}
+    }
+
+    /// Moves as much of a color as possible onto the white channel
+    ///
+    /// The part that all three channels share is exactly what the white LED
+    /// can produce, so it's taken out of each and sent as white instead. This
+    /// gives cleaner whites and pastels, and draws less power.
+    pub fn extract_white(color: Rgb8) -> Rgbw8 {
+        let w = color.r.min(color.g).min(color.b);
+
+        Rgbw8::new(color.r - w, color.g - w, color.b - w, w)
+    }
+}
+
+/// A 16 bit per channel RGB color
+///
+/// Used for intermediate math where 8 bits would throw away too much.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Rgb16 {
+    pub r: u16,
+    pub g: u16,
+    pub b: u16,
+}
+
+impl Rgb16 {
+    pub const fn new(r: u16, g: u16, b: u16) -> Rgb16 {
+        Rgb16 {r, g, b}
+    }
+}
+
+/// A 16 bit per channel RGB color with a separate white channel
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Rgbw16 {
+    pub r: u16,
+    pub g: u16,
+    pub b: u16,
+    pub w: u16,
+}
+
+impl Rgbw16 {
+    pub const fn new(r: u16, g: u16, b: u16, w: u16) -> Rgbw16 {
+        Rgbw16 {r, g, b, w}
+    }
+}
+
+/// A hue, saturation, value color
+///
+/// All three channels use the full 0-255 range, so a hue of 256 would wrap
+/// back around to red.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Hsv {
+    pub h: u8,
+    pub s: u8,
+    pub v: u8,
+}
+
+impl Hsv {
+    pub const fn new(h: u8, s: u8, v: u8) -> Hsv {
+        Hsv {h, s, v}
+    }
+}
+
+impl From<Rgb8> for Rgbw8 {
+    /// Leaves the white channel off. See `Rgbw8::extract_white` for using it.
+    fn from(value: Rgb8) -> Self {
+        Rgbw8::new(value.r, value.g, value.b, 0)
+    }
+}
+
+impl From<Rgbw8> for Rgb8 {
+    /// Drops the white channel
+    fn from(value: Rgbw8) -> Self {
+        Rgb8::new(value.r, value.g, value.b)
+    }
+}
+
+impl From<Rgb8> for Rgb16 {
+    fn from(value: Rgb8) -> Self {
+        // Multiplying by 257 maps 255 to 65535 exactly
+        Rgb16::new(value.r as u16 * 257, value.g as u16 * 257, value.b as u16 * 257)
+    }
+}
+
+impl From<Rgb16> for Rgb8 {
+    fn from(value: Rgb16) -> Self {
+        Rgb8::new((value.r >> 8) as u8, (value.g >> 8) as u8, (value.b >> 8) as u8)
+    }
+}
+
+impl From<Hsv> for Rgb8 {
+    fn from(value: Hsv) -> Self {
+        let Hsv {h, s, v} = value;
+
+        if s == 0 {
+            return Rgb8::new(v, v, v)
+        }
+
+        let (h, s, v) = (h as u16, s as u16, v as u16);
+
+        // Split the hue circle into six regions of 43
+        let region = h / 43;
+        let remainder = (h - region * 43) * 6;
+
+        let p = (v * (255 - s)) >> 8;
+        let q = (v * (255 - ((s * remainder) >> 8))) >> 8;
+        let t = (v * (255 - ((s * (255 - remainder)) >> 8))) >> 8;
+
+        let (r, g, b) = match region {
+            0 => (v, t, p),
+            1 => (q, v, p),
+            2 => (p, v, t),
+            3 => (p, q, v),
+            4 => (t, p, v),
+            _ => (v, p, q),
+        };
+
+        Rgb8::new(r as u8, g as u8, b as u8)
+    }
+}
+
+impl From<Rgb8> for Hsv {
+    fn from(value: Rgb8) -> Self {
+        let (r, g, b) = (value.r as i32, value.g as i32, value.b as i32);
+
+        let max = r.max(g).max(b);
+        let min = r.min(g).min(b);
+        let delta = max - min;
+
+        if delta == 0 {
+            return Hsv::new(0, 0, max as u8)
+        }
+
+        let s = 255 * delta / max;
+
+        // Same regions of 43 as the conversion back, so primaries round trip
+        let h = if max == r {
+            43 * (g - b) / delta
+        } else if max == g {
+            86 + 43 * (b - r) / delta
+        } else {
+            172 + 43 * (r - g) / delta
+        };
+
+        // Negative hues wrap back around from the top
+        Hsv::new(h.rem_euclid(256) as u8, s as u8, max as u8)
+    }
+}
+
+/// The order a strip expects its color channels to arrive in
+///
+/// WS2812B strips are GRB, but plenty of clones and other chips differ.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ColorOrder {
+    #[default]
+    Grb,
+    Rgb,
+    Brg,
+    Rbg,
+    Gbr,
+    Bgr,
+    Grbw,
+    Rgbw,
+    Brgw,
+    Wrgb,
+}
+
+impl ColorOrder {
+    /// Number of channels sent per pixel
+    pub fn channels(&self) -> u8 {
+        match self {
+            ColorOrder::Grb
+            | ColorOrder::Rgb
+            | ColorOrder::Brg
+            | ColorOrder::Rbg
+            | ColorOrder::Gbr
+            | ColorOrder::Bgr => 3,
+            ColorOrder::Grbw
+            | ColorOrder::Rgbw
+            | ColorOrder::Brgw
+            | ColorOrder::Wrgb => 4,
+        }
+    }
+
+    /// Number of bits sent per pixel
+    pub fn bits_per_pixel(&self) -> u8 {
+        self.channels() * 8
+    }
+
+    /// Packs a color into a FIFO word
+    ///
+    /// The first channel on the wire ends up in the most significant byte.
+    /// Orders without a white channel ignore `w` and leave the bottom byte
+    /// empty.
+    pub fn pack(&self, color: Rgbw8) -> u32 {
+        let Rgbw8 {r, g, b, w} = color;
+
+        let bytes = match self {
+            ColorOrder::Grb => [g, r, b, 0],
+            ColorOrder::Rgb => [r, g, b, 0],
+            ColorOrder::Brg => [b, r, g, 0],
+            ColorOrder::Rbg => [r, b, g, 0],
+            ColorOrder::Gbr => [g, b, r, 0],
+            ColorOrder::Bgr => [b, g, r, 0],
+            ColorOrder::Grbw => [g, r, b, w],
+            ColorOrder::Rgbw => [r, g, b, w],
+            ColorOrder::Brgw => [b, r, g, w],
+            ColorOrder::Wrgb => [w, r, g, b],
+        };
+
+        u32::from_be_bytes(bytes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn primaries_round_trip_through_hsv() {
+        let primaries = [
+            (Rgb8::new(255, 0, 0), Hsv::new(0, 255, 255)),
+            (Rgb8::new(0, 255, 0), Hsv::new(86, 255, 255)),
+            (Rgb8::new(0, 0, 255), Hsv::new(172, 255, 255)),
+        ];
+
+        for (rgb, hsv) in primaries {
+            assert_eq!(Hsv::from(rgb), hsv);
+            assert_eq!(Rgb8::from(hsv), rgb);
+        }
+
+        assert_eq!(Hsv::from(Rgb8::new(40, 40, 40)), Hsv::new(0, 0, 40));
+        assert_eq!(Rgb8::from(Hsv::new(123, 0, 40)), Rgb8::new(40, 40, 40));
+    }
+
+    #[test]
+    fn pack_puts_the_first_channel_on_top() {
+        let color = Rgbw8::new(0x11, 0x22, 0x33, 0x44);
+
+        let expected = [
+            (ColorOrder::Grb, 0x22113300),
+            (ColorOrder::Rgb, 0x11223300),
+            (ColorOrder::Brg, 0x33112200),
+            (ColorOrder::Rbg, 0x11332200),
+            (ColorOrder::Gbr, 0x22331100),
+            (ColorOrder::Bgr, 0x33221100),
+            (ColorOrder::Grbw, 0x22113344),
+            (ColorOrder::Rgbw, 0x11223344),
+            (ColorOrder::Brgw, 0x33112244),
+            (ColorOrder::Wrgb, 0x44112233),
+        ];
+
+        for (order, word) in expected {
+            assert_eq!(order.pack(color), word, "{order:?}");
+        }
+    }
+
+    #[test]
+    fn extract_white_takes_the_shared_part() {
+        assert_eq!(Rgbw8::extract_white(Rgb8::new(200, 150, 100)), Rgbw8::new(100, 50, 0, 100));
+        assert_eq!(Rgbw8::extract_white(Rgb8::new(255, 0, 255)), Rgbw8::new(255, 0, 255, 0));
+    }
+}
@@ -0,0 +1,222 @@
+//! Gamma correction and global brightness
+//!
+//! LEDs are linear in PWM duty cycle, but eyes are not, so a fade that steps
+//! evenly through 0-255 looks like it jumps straight to full brightness. The
+//! gamma tables map an 8 bit channel to a 16 bit linear one, which leaves
+//! enough headroom for brightness scaling to not crush the dim end.
+
+use crate::color::{Rgb16, Rgb8, Rgbw16, Rgbw8};
+
+/// Which gamma curve to correct with
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Gamma {
+    /// No correction at all
+    Linear,
+    /// Matches sRGB closely
+    Gamma2_2,
+    /// A good middle ground for most strips
+    #[default]
+    Gamma2_5,
+    /// Stronger correction that keeps dim colors dim
+    Gamma2_8,
+}
+
+impl Gamma {
+    /// Maps an 8 bit channel onto the curve, scaled up to 16 bits
+    pub fn apply(&self, value: u8) -> u16 {
+        match self {
+            Gamma::Linear => value as u16 * 257,
+            Gamma::Gamma2_2 => GAMMA_2_2[value as usize],
+            Gamma::Gamma2_5 => GAMMA_2_5[value as usize],
+            Gamma::Gamma2_8 => GAMMA_2_8[value as usize],
+        }
+    }
+}
+
+/// Gamma and brightness settings for a strip's output stage
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Correction {
+    pub gamma: Gamma,
+    pub brightness: u8,
+}
+
+impl Correction {
+    pub const fn new(gamma: Gamma, brightness: u8) -> Correction {
+        Correction {gamma, brightness}
+    }
+
+    /// Corrects a single channel, keeping the full 16 bits
+    pub fn channel(&self, value: u8) -> u16 {
+        let value = self.gamma.apply(value) as u32;
+
+        // Maps 0-255 onto 0-256 so full brightness leaves the value untouched
+        let brightness = self.brightness as u32;
+        let scale = brightness + (brightness >> 7);
+
+        ((value * scale) >> 8) as u16
+    }
+
+    /// Corrects a color, keeping the full 16 bits per channel
+    pub fn apply(&self, color: Rgb8) -> Rgb16 {
+        Rgb16::new(self.channel(color.r), self.channel(color.g), self.channel(color.b))
+    }
+
+    /// Corrects a color with a white channel, keeping the full 16 bits per
+    /// channel
+    pub fn apply_rgbw(&self, color: Rgbw8) -> Rgbw16 {
+        Rgbw16::new(
+            self.channel(color.r),
+            self.channel(color.g),
+            self.channel(color.b),
+            self.channel(color.w),
+        )
+    }
+}
+
+impl Default for Correction {
+    fn default() -> Self {
+        Correction::new(Gamma::default(), 255)
+    }
+}
+
+static GAMMA_2_2: [u16; 256] = [
+        0,     0,     2,     4,     7,    11,    17,    24,
+       32,    42,    53,    65,    79,    94,   111,   129,
+      148,   169,   192,   216,   242,   270,   299,   330,
+      362,   396,   432,   469,   508,   549,   591,   635,
+      681,   729,   779,   830,   883,   938,   995,  1053,
+     1113,  1175,  1239,  1305,  1373,  1443,  1514,  1587,
+     1663,  1740,  1819,  1900,  1983,  2068,  2155,  2243,
+     2334,  2427,  2521,  2618,  2717,  2817,  2920,  3024,
+     3131,  3240,  3350,  3463,  3578,  3694,  3813,  3934,
+     4057,  4182,  4309,  4438,  4570,  4703,  4838,  4976,
+     5115,  5257,  5401,  5547,  5695,  5845,  5998,  6152,
+     6309,  6468,  6629,  6792,  6957,  7124,  7294,  7466,
+     7640,  7816,  7994,  8175,  8358,  8543,  8730,  8919,
+     9111,  9305,  9501,  9699,  9900, 10102, 10307, 10515,
+    10724, 10936, 11150, 11366, 11585, 11806, 12029, 12254,
+    12482, 12712, 12944, 13179, 13416, 13655, 13896, 14140,
+    14386, 14635, 14885, 15138, 15394, 15652, 15912, 16174,
+    16439, 16706, 16975, 17247, 17521, 17798, 18077, 18358,
+    18642, 18928, 19216, 19507, 19800, 20095, 20393, 20694,
+    20996, 21301, 21609, 21919, 22231, 22546, 22863, 23182,
+    23504, 23829, 24156, 24485, 24817, 25151, 25487, 25826,
+    26168, 26512, 26858, 27207, 27558, 27912, 28268, 28627,
+    28988, 29351, 29717, 30086, 30457, 30830, 31206, 31585,
+    31966, 32349, 32735, 33124, 33514, 33908, 34304, 34702,
+    35103, 35507, 35913, 36321, 36732, 37146, 37562, 37981,
+    38402, 38825, 39252, 39680, 40112, 40546, 40982, 41421,
+    41862, 42306, 42753, 43202, 43654, 44108, 44565, 45025,
+    45487, 45951, 46418, 46888, 47360, 47835, 48313, 48793,
+    49275, 49761, 50249, 50739, 51232, 51728, 52226, 52727,
+    53230, 53736, 54245, 54756, 55270, 55787, 56306, 56828,
+    57352, 57879, 58409, 58941, 59476, 60014, 60554, 61097,
+    61642, 62190, 62741, 63295, 63851, 64410, 64971, 65535,
+];
+
+static GAMMA_2_5: [u16; 256] = [
+        0,     0,     0,     1,     2,     4,     6,     8,
+       11,    15,    20,    25,    31,    38,    46,    55,
+       65,    75,    87,    99,   113,   128,   143,   160,
+      178,   197,   218,   239,   262,   286,   311,   338,
+      366,   395,   425,   457,   491,   526,   562,   599,
+      639,   679,   722,   765,   811,   857,   906,   956,
+     1007,  1061,  1116,  1172,  1231,  1291,  1352,  1416,
+     1481,  1548,  1617,  1688,  1760,  1834,  1910,  1988,
+     2068,  2150,  2233,  2319,  2407,  2496,  2587,  2681,
+     2776,  2874,  2973,  3075,  3178,  3284,  3391,  3501,
+     3613,  3727,  3843,  3961,  4082,  4204,  4329,  4456,
+     4585,  4716,  4850,  4986,  5124,  5264,  5407,  5552,
+     5699,  5849,  6001,  6155,  6311,  6470,  6632,  6795,
+     6962,  7130,  7301,  7475,  7650,  7829,  8009,  8193,
+     8379,  8567,  8758,  8951,  9147,  9345,  9546,  9750,
+     9956, 10165, 10376, 10590, 10806, 11025, 11247, 11472,
+    11699, 11929, 12161, 12397, 12634, 12875, 13119, 13365,
+    13614, 13865, 14120, 14377, 14637, 14899, 15165, 15433,
+    15705, 15979, 16256, 16535, 16818, 17104, 17392, 17683,
+    17978, 18275, 18575, 18878, 19184, 19493, 19805, 20119,
+    20437, 20758, 21082, 21409, 21739, 22072, 22407, 22746,
+    23089, 23434, 23782, 24133, 24487, 24845, 25206, 25569,
+    25936, 26306, 26679, 27055, 27435, 27818, 28203, 28592,
+    28985, 29380, 29779, 30181, 30586, 30994, 31406, 31820,
+    32239, 32660, 33085, 33513, 33944, 34379, 34817, 35258,
+    35702, 36150, 36602, 37056, 37514, 37976, 38441, 38909,
+    39380, 39856, 40334, 40816, 41301, 41790, 42282, 42778,
+    43277, 43780, 44286, 44795, 45308, 45825, 46345, 46869,
+    47396, 47927, 48461, 48999, 49540, 50085, 50634, 51186,
+    51742, 52301, 52864, 53431, 54001, 54575, 55153, 55734,
+    56318, 56907, 57499, 58095, 58695, 59298, 59905, 60515,
+    61130, 61748, 62370, 62995, 63624, 64258, 64894, 65535,
+];
+
+static GAMMA_2_8: [u16; 256] = [
+        0,     0,     0,     0,     1,     1,     2,     3,
+        4,     6,     8,    10,    13,    16,    19,    24,
+       28,    33,    39,    46,    53,    60,    69,    78,
+       88,    98,   110,   122,   135,   149,   164,   179,
+      196,   214,   232,   252,   273,   295,   317,   341,
+      366,   393,   420,   449,   478,   510,   542,   575,
+      610,   647,   684,   723,   764,   806,   849,   894,
+      940,   988,  1037,  1088,  1140,  1194,  1250,  1307,
+     1366,  1427,  1489,  1553,  1619,  1686,  1756,  1827,
+     1900,  1975,  2051,  2130,  2210,  2293,  2377,  2463,
+     2552,  2642,  2734,  2829,  2925,  3024,  3124,  3227,
+     3332,  3439,  3548,  3660,  3774,  3890,  4008,  4128,
+     4251,  4376,  4504,  4634,  4766,  4901,  5038,  5177,
+     5319,  5464,  5611,  5760,  5912,  6067,  6224,  6384,
+     6546,  6711,  6879,  7049,  7222,  7397,  7576,  7757,
+     7941,  8128,  8317,  8509,  8704,  8902,  9103,  9307,
+     9514,  9723,  9936, 10151, 10370, 10591, 10816, 11043,
+    11274, 11507, 11744, 11984, 12227, 12473, 12722, 12975,
+    13230, 13489, 13751, 14017, 14285, 14557, 14833, 15111,
+    15393, 15678, 15967, 16259, 16554, 16853, 17155, 17461,
+    17770, 18083, 18399, 18719, 19042, 19369, 19700, 20034,
+    20372, 20713, 21058, 21407, 21759, 22115, 22475, 22838,
+    23206, 23577, 23952, 24330, 24713, 25099, 25489, 25884,
+    26282, 26683, 27089, 27499, 27913, 28330, 28752, 29178,
+    29608, 30041, 30479, 30921, 31367, 31818, 32272, 32730,
+    33193, 33660, 34131, 34606, 35085, 35569, 36057, 36549,
+    37046, 37547, 38052, 38561, 39075, 39593, 40116, 40643,
+    41175, 41711, 42251, 42796, 43346, 43899, 44458, 45021,
+    45588, 46161, 46737, 47319, 47905, 48495, 49091, 49691,
+    50295, 50905, 51519, 52138, 52761, 53390, 54023, 54661,
+    55303, 55951, 56604, 57261, 57923, 58590, 59262, 59939,
+    60621, 61308, 62000, 62697, 63399, 64106, 64818, 65535,
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GAMMAS: [Gamma; 4] = [Gamma::Linear, Gamma::Gamma2_2, Gamma::Gamma2_5, Gamma::Gamma2_8];
+
+    #[test]
+    fn every_curve_spans_the_full_range() {
+        for gamma in GAMMAS {
+            assert_eq!(gamma.apply(0), 0, "{gamma:?}");
+            assert_eq!(gamma.apply(255), u16::MAX, "{gamma:?}");
+
+            assert!((1..=255).all(|value| gamma.apply(value) >= gamma.apply(value - 1)), "{gamma:?}");
+        }
+    }
+
+    #[test]
+    fn full_brightness_leaves_the_curve_alone() {
+        for gamma in GAMMAS {
+            let correction = Correction::new(gamma, 255);
+
+            for value in 0..=255 {
+                assert_eq!(correction.channel(value), gamma.apply(value), "{gamma:?} {value}");
+            }
+        }
+    }
+
+    #[test]
+    fn zero_brightness_is_off() {
+        for gamma in GAMMAS {
+            let correction = Correction::new(gamma, 0);
+
+            assert!((0..=255).all(|value| correction.channel(value) == 0), "{gamma:?}");
+        }
+    }
+}
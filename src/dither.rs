@@ -0,0 +1,135 @@
+//! Temporal dithering
+//!
+//! After gamma and brightness, most of the detail in a dim color lives in the
+//! bottom 8 bits of its 16 bit channels, which would get thrown away when the
+//! frame is packed. Dithering carries that remainder over to the next frame
+//! instead, so a channel stuck between two steps flickers between them fast
+//! enough to look like the value in between.
+//!
+//! This only works if frames keep getting sent, even when nothing changed.
+
+use alloc::vec::Vec;
+
+use crate::color::{Rgbw16, Rgbw8};
+
+/// Accumulated error for every channel of every pixel, in 65535ths of an
+/// 8 bit step
+#[derive(Debug, Default)]
+pub struct Dither {
+    error: Vec<[u16; 4]>,
+}
+
+impl Dither {
+    pub fn new() -> Dither {
+        Dither {error: Vec::new()}
+    }
+
+    /// Forgets all accumulated error
+    pub fn reset(&mut self) {
+        self.error.clear();
+    }
+
+    /// Reduces a pixel to 8 bits, carrying the remainder over to the next
+    /// frame
+    ///
+    /// * `index` - Position of the pixel in the strip
+    pub fn quantize(&mut self, index: usize, color: Rgbw16) -> Rgbw8 {
+        if index >= self.error.len() {
+            self.error.resize(index + 1, [0; 4]);
+        }
+
+        let [r, g, b, w] = &mut self.error[index];
+
+        Rgbw8::new(
+            quantize_channel(color.r, r),
+            quantize_channel(color.g, g),
+            quantize_channel(color.b, b),
+            quantize_channel(color.w, w),
+        )
+    }
+}
+
+/// Rounds a pixel to the nearest 8 bit value without dithering
+pub fn round(color: Rgbw16) -> Rgbw8 {
+    Rgbw8::new(
+        round_channel(color.r),
+        round_channel(color.g),
+        round_channel(color.b),
+        round_channel(color.w),
+    )
+}
+
+fn quantize_channel(value: u16, error: &mut u16) -> u8 {
+    // Anything that didn't fit gets added back in next frame
+    let (output, remainder) = scale(value, *error);
+    *error = remainder;
+    output
+}
+
+fn round_channel(value: u16) -> u8 {
+    // Half a step, so it rounds rather than truncating
+    scale(value, 32767).0
+}
+
+/// Scales a channel down to 8 bits, plus `error` 65535ths of a step, and
+/// returns what was left over in the same units
+///
+/// This scales by 255 / 65535 so that 8 bit values widened by 257 come back
+/// unchanged.
+fn scale(value: u16, error: u16) -> (u8, u16) {
+    let total = value as u32 * 255 + error as u32;
+    ((total / 65535) as u8, (total % 65535) as u16)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn averages_out_to_the_16_bit_value() {
+        for value in [1, 100, 255, 257, 0x1234, 32896, 0xff00] {
+            let mut dither = Dither::new();
+            let color = Rgbw16::new(value, value, value, value);
+
+            let sum: u32 = (0..257).map(|_| dither.quantize(0, color).r as u32).sum();
+
+            // 257 frames of an 8 bit output add up to the 16 bit input
+            assert_eq!(sum, value as u32, "{value}");
+        }
+    }
+
+    #[test]
+    fn widened_values_dont_flicker() {
+        let mut dither = Dither::new();
+
+        for value in 0..=255u8 {
+            let wide = value as u16 * 257;
+            let color = Rgbw16::new(wide, wide, wide, wide);
+
+            for _ in 0..4 {
+                assert_eq!(dither.quantize(0, color), round(color));
+            }
+        }
+    }
+
+    #[test]
+    fn pixels_carry_their_own_error() {
+        let mut dither = Dither::new();
+        let dim = Rgbw16::new(200, 0, 0, 0);
+
+        assert_eq!(dither.quantize(0, dim).r, 0);
+        assert_eq!(dither.quantize(1, Rgbw16::default()).r, 0);
+        assert_eq!(dither.quantize(0, dim).r, 1);
+
+        dither.reset();
+        assert_eq!(dither.quantize(0, dim).r, 0);
+    }
+
+    #[test]
+    fn round_undoes_widening() {
+        for value in 0..=255u8 {
+            let wide = value as u16 * 257;
+            assert_eq!(round(Rgbw16::new(wide, wide, wide, wide)), Rgbw8::new(value, value, value, value));
+        }
+    }
+}
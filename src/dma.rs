@@ -0,0 +1,144 @@
+//! Streams frame buffers into PIO FIFOs with DMA
+//!
+//! The rp2040_hal DMA API types every transfer by its channel, source, and
+//! destination, which doesn't mix with the `Tx` enum. This drives the channel
+//! registers directly instead, so any channel can feed any state machine.
+
+use core::sync::atomic::{compiler_fence, Ordering};
+
+use rp2040_hal::dma::{Channel, ChannelIndex, SingleChannel};
+use rp2040_hal::pac;
+
+use crate::tx::Tx;
+
+/// Number of DMA channels on the RP2040
+pub const NUM_CHANNELS: usize = 12;
+
+/// Callbacks to run from the DMA interrupt when a channel finishes
+static mut CALLBACKS: [Option<fn()>; NUM_CHANNELS] = [None; NUM_CHANNELS];
+
+/// A single DMA channel
+pub struct DmaChannel {
+    id: u8,
+}
+
+impl DmaChannel {
+    /// The index of this channel
+    pub fn id(&self) -> u8 {
+        self.id
+    }
+
+    /// Starts streaming a buffer into a tx FIFO
+    ///
+    /// The transfer is paced by the state machine's DREQ, so it only moves a
+    /// word whenever there is room in the FIFO.
+    ///
+    /// # Safety
+    /// The DMA keeps reading `words` after this returns. The buffer must not be
+    /// dropped or written to until `is_busy` returns false.
+    pub unsafe fn start(&mut self, words: &[u32], tx: &Tx) {
+        let ch = self.registers();
+
+        ch.ch_read_addr().write(|w| w.bits(words.as_ptr() as u32));
+        ch.ch_write_addr().write(|w| w.bits(tx.fifo_address() as u32));
+        ch.ch_trans_count().write(|w| w.bits(words.len() as u32));
+
+        // Everything written to the buffer has to land before the DMA reads it
+        compiler_fence(Ordering::SeqCst);
+
+        // Writing the control register triggers the transfer
+        ch.ch_ctrl_trig().write(|w| {
+            w.data_size().size_word();
+            w.incr_read().set_bit();
+            w.incr_write().clear_bit();
+            w.treq_sel().bits(tx.dreq_value());
+            w.chain_to().bits(self.id);
+            w.en().set_bit();
+            w
+        });
+    }
+
+    /// Returns whether or not a transfer is still running
+    pub fn is_busy(&self) -> bool {
+        self.registers().ch_ctrl_trig().read().busy().bit_is_set()
+    }
+
+    /// Returns whether or not the last transfer has finished
+    pub fn is_done(&self) -> bool {
+        !self.is_busy()
+    }
+
+    /// Blocks until the current transfer finishes
+    pub fn wait(&self) {
+        while self.is_busy() {}
+    }
+
+    /// Stops the current transfer
+    pub fn abort(&mut self) {
+        let dma = unsafe { &*pac::DMA::ptr() };
+        let mask = 1 << self.id;
+
+        dma.chan_abort().write(|w| unsafe { w.bits(mask) });
+        while dma.chan_abort().read().bits() & mask != 0 {}
+    }
+
+    /// Sets a function to call from the DMA interrupt whenever a transfer on
+    /// this channel finishes
+    ///
+    /// Passing `None` turns the interrupt back off for this channel. The
+    /// callbacks only run if the application's `DMA_IRQ_0` handler calls
+    /// `on_irq`.
+    pub fn set_callback(&mut self, callback: Option<fn()>) {
+        let dma = unsafe { &*pac::DMA::ptr() };
+        let mask = 1 << self.id;
+
+        critical_section::with(|_| unsafe {
+            CALLBACKS[self.id as usize] = callback;
+
+            dma.inte0().modify(|r, w| match callback {
+                Some(_) => w.bits(r.bits() | mask),
+                None => w.bits(r.bits() & !mask),
+            });
+
+            if callback.is_some() {
+                pac::NVIC::unmask(pac::Interrupt::DMA_IRQ_0);
+            }
+        });
+    }
+
+    fn registers(&self) -> &pac::dma::CH {
+        unsafe { (*pac::DMA::ptr()).ch(self.id as usize) }
+    }
+}
+
+impl<CH: ChannelIndex> From<Channel<CH>> for DmaChannel {
+    fn from(value: Channel<CH>) -> Self {DmaChannel {id: value.id()}}
+}
+
+/// Runs the callbacks of every channel that just finished
+///
+/// The library leaves the interrupt vectors to the application, so this has to
+/// be called from its `DMA_IRQ_0` handler.
+///
+/// # Safety
+/// Only call this from the `DMA_IRQ_0` handler.
+pub unsafe fn on_irq() {
+    let dma = &*pac::DMA::ptr();
+
+    // Acknowledge everything up front so nothing gets missed while the
+    // callbacks run
+    let status = dma.ints0().read().bits();
+    dma.ints0().write(|w| w.bits(status));
+
+    let callbacks = CALLBACKS;
+
+    for (id, callback) in callbacks.iter().enumerate() {
+        if status & (1 << id) == 0 {
+            continue
+        }
+
+        if let Some(callback) = callback {
+            callback();
+        }
+    }
+}
@@ -0,0 +1,149 @@
+//! Animations that don't care what they're drawn on
+//!
+//! An effect only ever fills in a slice of colors for a point in time, so the
+//! same code can feed a `Strip` on the board or `strip_sim` on the host. The
+//! `Layout` says how the slice maps onto the LEDs, for effects that work in
+//! two dimensions.
+//!
+//! ```ignore
+//! let layout = Layout::strip(60)?;
+//! let mut pixels = vec![Rgb8::default(); layout.len()];
+//!
+//! effect.render(time_us, &layout, &mut pixels);
+//! strip.write(&pixels);
+//! strip.present();
+//! ```
+
+use crate::color::Rgb8;
+
+#[derive(Debug)]
+pub enum Error {
+    /// A layout has to be at least one LED wide
+    ZeroWidth,
+    /// A layout has to be at least one row high
+    ZeroHeight,
+}
+
+/// How a run of LEDs is laid out physically
+///
+/// A strip is a matrix that's a single row high.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Layout {
+    width: usize,
+    height: usize,
+    /// Whether every other row is wired right to left, as is common on panels
+    /// made from a single strip folded back on itself
+    serpentine: bool,
+}
+
+impl Layout {
+    /// A straight line of LEDs
+    pub fn strip(len: usize) -> Result<Layout, Error> {
+        Layout::matrix(len, 1, false)
+    }
+
+    /// A grid of LEDs wired row by row from the top left
+    pub fn matrix(width: usize, height: usize, serpentine: bool) -> Result<Layout, Error> {
+        if width == 0 {
+            return Err(Error::ZeroWidth)
+        }
+
+        if height == 0 {
+            return Err(Error::ZeroHeight)
+        }
+
+        Ok(Layout {width, height, serpentine})
+    }
+
+    /// Total number of LEDs
+    pub fn len(&self) -> usize {
+        let (width, height) = self.size();
+        width * height
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Width and height, with a strip being a single row
+    pub fn size(&self) -> (usize, usize) {
+        (self.width, self.height)
+    }
+
+    /// Whether every other row runs right to left
+    pub fn is_serpentine(&self) -> bool {
+        self.serpentine
+    }
+
+    /// Index into the pixel slice of the LED at a position
+    ///
+    /// Returns `None` if the position is off the edge.
+    pub fn index(&self, x: usize, y: usize) -> Option<usize> {
+        let (width, height) = self.size();
+
+        if x >= width || y >= height {
+            return None
+        }
+
+        let x = match self.serpentine && y % 2 == 1 {
+            true => width - 1 - x,
+            false => x,
+        };
+
+        Some(y * width + x)
+    }
+
+    /// Position of the LED at an index into the pixel slice
+    pub fn position(&self, index: usize) -> (usize, usize) {
+        let (width, _) = self.size();
+        let (x, y) = (index % width, index / width);
+
+        match self.serpentine && y % 2 == 1 {
+            true => (width - 1 - x, y),
+            false => (x, y),
+        }
+    }
+}
+
+/// Something that draws frames
+pub trait Effect {
+    /// Fills in every pixel for a moment in time
+    ///
+    /// * `time_us` - Microseconds since the effect started
+    /// * `layout` - How `pixels` maps onto the LEDs
+    /// * `pixels` - One color per LED, in wiring order. Holds the last frame
+    ///   when this is called, for effects that build on it.
+    fn render(&mut self, time_us: u64, layout: &Layout, pixels: &mut [Rgb8]);
+}
+
+impl<F: FnMut(u64, &Layout, &mut [Rgb8])> Effect for F {
+    fn render(&mut self, time_us: u64, layout: &Layout, pixels: &mut [Rgb8]) {
+        self(time_us, layout, pixels)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn serpentine_rows_run_backwards() {
+        let layout = Layout::matrix(3, 2, true).unwrap();
+
+        assert_eq!(layout.index(0, 1), Some(5));
+        assert_eq!(layout.index(2, 1), Some(3));
+        assert_eq!(layout.index(3, 0), None);
+
+        for index in 0..layout.len() {
+            let (x, y) = layout.position(index);
+            assert_eq!(layout.index(x, y), Some(index));
+        }
+    }
+
+    #[test]
+    fn empty_layouts_are_rejected() {
+        assert!(matches!(Layout::strip(0), Err(Error::ZeroWidth)));
+        assert!(matches!(Layout::matrix(0, 4, false), Err(Error::ZeroWidth)));
+        assert!(matches!(Layout::matrix(4, 0, false), Err(Error::ZeroHeight)));
+    }
+}
@@ -0,0 +1,112 @@
+//! Shared plumbing for the `Tx` and `Rx` enums
+//!
+//! The HAL gives every state machine's FIFOs their own type, so a FIFO can't
+//! be stored without knowing at compile time which block and state machine
+//! it came from. `fifo!` wraps all eight in a single enum, and `dispatch!`
+//! forwards a call to whichever one it's holding.
+
+use crate::hal::pac::{PIO0, PIO1};
+
+/// Which way a FIFO carries words
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Direction {
+    /// From the CPU to the state machine
+    Tx,
+    /// From the state machine to the CPU
+    Rx,
+}
+
+/// Declares an enum with a variant for each state machine's HAL FIFO type,
+/// along with `From` impls and the methods that don't touch the FIFO itself
+macro_rules! fifo {
+    ($(#[$meta:meta])* $name:ident, $inner:ident, $direction:expr) => {
+        $(#[$meta])*
+        pub enum $name {
+            PIO0SM0($crate::hal::pio::$inner<($crate::hal::pac::PIO0, $crate::hal::pio::SM0)>),
+            PIO0SM1($crate::hal::pio::$inner<($crate::hal::pac::PIO0, $crate::hal::pio::SM1)>),
+            PIO0SM2($crate::hal::pio::$inner<($crate::hal::pac::PIO0, $crate::hal::pio::SM2)>),
+            PIO0SM3($crate::hal::pio::$inner<($crate::hal::pac::PIO0, $crate::hal::pio::SM3)>),
+            PIO1SM0($crate::hal::pio::$inner<($crate::hal::pac::PIO1, $crate::hal::pio::SM0)>),
+            PIO1SM1($crate::hal::pio::$inner<($crate::hal::pac::PIO1, $crate::hal::pio::SM1)>),
+            PIO1SM2($crate::hal::pio::$inner<($crate::hal::pac::PIO1, $crate::hal::pio::SM2)>),
+            PIO1SM3($crate::hal::pio::$inner<($crate::hal::pac::PIO1, $crate::hal::pio::SM3)>),
+        }
+
+        $crate::fifo::fifo!(@from $name, $inner, PIO0, SM0, PIO0SM0);
+        $crate::fifo::fifo!(@from $name, $inner, PIO0, SM1, PIO0SM1);
+        $crate::fifo::fifo!(@from $name, $inner, PIO0, SM2, PIO0SM2);
+        $crate::fifo::fifo!(@from $name, $inner, PIO0, SM3, PIO0SM3);
+        $crate::fifo::fifo!(@from $name, $inner, PIO1, SM0, PIO1SM0);
+        $crate::fifo::fifo!(@from $name, $inner, PIO1, SM1, PIO1SM1);
+        $crate::fifo::fifo!(@from $name, $inner, PIO1, SM2, PIO1SM2);
+        $crate::fifo::fifo!(@from $name, $inner, PIO1, SM3, PIO1SM3);
+
+        impl $name {
+            /// Which PIO block the FIFO belongs to, 0 or 1
+            pub fn block(&self) -> u8 {
+                match self {
+                    $name::PIO0SM0(_) | $name::PIO0SM1(_) | $name::PIO0SM2(_) | $name::PIO0SM3(_) => 0,
+                    $name::PIO1SM0(_) | $name::PIO1SM1(_) | $name::PIO1SM2(_) | $name::PIO1SM3(_) => 1,
+                }
+            }
+
+            /// Index of the state machine within its block
+            pub fn index(&self) -> u8 {
+                match self {
+                    $name::PIO0SM0(_) | $name::PIO1SM0(_) => 0,
+                    $name::PIO0SM1(_) | $name::PIO1SM1(_) => 1,
+                    $name::PIO0SM2(_) | $name::PIO1SM2(_) => 2,
+                    $name::PIO0SM3(_) | $name::PIO1SM3(_) => 3,
+                }
+            }
+
+            /// Number of words sitting in the FIFO
+            pub fn level(&self) -> u8 {
+                $crate::fifo::level(self.block(), self.index(), $direction)
+            }
+        }
+    };
+    (@from $name:ident, $inner:ident, $pio:ident, $sm:ident, $variant:ident) => {
+        impl From<$crate::hal::pio::$inner<($crate::hal::pac::$pio, $crate::hal::pio::$sm)>> for $name {
+            fn from(value: $crate::hal::pio::$inner<($crate::hal::pac::$pio, $crate::hal::pio::$sm)>) -> Self {$name::$variant(value)}
+        }
+    };
+}
+
+/// Runs the same expression on whichever HAL FIFO a `Tx` or `Rx` is holding
+///
+/// `dispatch!(self, tx => tx.write(value))`
+macro_rules! dispatch {
+    ($value:expr, $fifo:ident => $body:expr) => {
+        match $value {
+            Self::PIO0SM0($fifo) => $body,
+            Self::PIO0SM1($fifo) => $body,
+            Self::PIO0SM2($fifo) => $body,
+            Self::PIO0SM3($fifo) => $body,
+            Self::PIO1SM0($fifo) => $body,
+            Self::PIO1SM1($fifo) => $body,
+            Self::PIO1SM2($fifo) => $body,
+            Self::PIO1SM3($fifo) => $body,
+        }
+    };
+}
+
+pub(crate) use {dispatch, fifo};
+
+/// Reads how many words are in one FIFO from a block's FLEVEL register
+pub fn level(block: u8, index: u8, direction: Direction) -> u8 {
+    let flevel = unsafe {
+        match block {
+            0 => (*PIO0::ptr()).flevel().read().bits(),
+            _ => (*PIO1::ptr()).flevel().read().bits(),
+        }
+    };
+
+    // Each state machine has a byte, tx in the low nibble and rx in the high
+    let shift = index as u32 * 8 + match direction {
+        Direction::Tx => 0,
+        Direction::Rx => 4,
+    };
+
+    (flevel >> shift) as u8 & 0xf
+}
@@ -0,0 +1,13 @@
+//! The parts of rp2040_hal the library is built on
+//!
+//! With the `host` feature these are the stand-ins from `mock` instead, which
+//! have the same names and the same shape as far as the rest of the library
+//! can tell.
+
+#[cfg(not(feature = "host"))]
+pub use cortex_m::delay::Delay;
+#[cfg(not(feature = "host"))]
+pub use rp2040_hal::{gpio, pac, pio, Timer};
+
+#[cfg(feature = "host")]
+pub use crate::mock::{delay::Delay, gpio, pac, pio, timer::Timer};
@@ -1,27 +1,34 @@
 //! Handles most low level hardware abstraction
 
+use alloc::vec::Vec;
 use core::cell::RefCell;
 
 use cortex_m::delay::Delay;
 use rp2040_hal::{clocks::init_clocks_and_plls, gpio::{bank0::{
     Gpio0, Gpio1, Gpio10, Gpio11, Gpio12, Gpio13, Gpio14, Gpio15, Gpio16, Gpio17, Gpio18, Gpio19, Gpio2, Gpio20, Gpio21, Gpio22, Gpio23, Gpio24, Gpio25, Gpio26, Gpio27, Gpio28, Gpio29, Gpio3, Gpio4, Gpio5, Gpio6, Gpio7, Gpio8, Gpio9
-}, FunctionNull, Pin, PullDown}, pac::{self, PIO0, PIO1}, usb::UsbBus, Clock, Sio, Watchdog};
+}, DynPinId, FunctionNull, Pin, PullDown}, dma::DMAExt, pac::{self, PIO0, PIO1}, usb::UsbBus, Clock, Sio, Timer, Watchdog};
 use usb_device::class_prelude::UsbBusAllocator;
 
-use crate::{pio::Pio, usb_manager::UsbManager};
+use crate::{dma::DmaChannel, pio::Pio, usb_manager::UsbManager};
 
 static mut SINGLETON: Option<Hardware> = None;
 
 type OptCell<T> = RefCell<Option<T>>;
 type P<T> = Pin<T, FunctionNull, PullDown>;
 
+/// A pin whose number is only known at runtime
+pub type DynPin = Pin<DynPinId, FunctionNull, PullDown>;
+
 #[derive(Debug)]
 pub enum Error {
     AttemptToReturnExistingValue,
+    /// There is no pin with that number
+    InvalidPin,
 }
 
 pub struct Hardware {
     delay: OptCell<Delay>,
+    dma_channels: RefCell<Vec<DmaChannel>>,
     pin0: OptCell<P<Gpio0>>,
     pin1: OptCell<P<Gpio1>>,
     pin2: OptCell<P<Gpio2>>,
@@ -56,6 +63,8 @@ pub struct Hardware {
     pio1: OptCell<Pio<PIO1>>,
     usb: OptCell<UsbManager>,
     usb_bus: UsbBusAllocator<UsbBus>,
+    timer: Timer,
+    system_clock_hz: u32,
 }
 
 impl Hardware {
@@ -79,12 +88,15 @@ impl Hardware {
             .ok()
             .unwrap();
 
+            let system_clock_hz = clocks.system_clock.freq().to_Hz();
+            let timer = Timer::new(pac.TIMER, &mut pac.RESETS, &clocks);
+
             let delay;
             let usb;
             let usb_bus;
 
             unsafe {
-                delay = cortex_m::delay::Delay::new(core.SYST, clocks.system_clock.freq().to_Hz());
+                delay = cortex_m::delay::Delay::new(core.SYST, system_clock_hz);
 
                 usb_bus = UsbBusAllocator::new(UsbBus::new(
                     pac.USBCTRL_REGS,
@@ -105,12 +117,20 @@ impl Hardware {
                 &mut pac.RESETS,
             );
 
+            let dma = pac.DMA.split(&mut pac.RESETS);
+            let dma_channels = alloc::vec![
+                dma.ch0.into(), dma.ch1.into(), dma.ch2.into(), dma.ch3.into(),
+                dma.ch4.into(), dma.ch5.into(), dma.ch6.into(), dma.ch7.into(),
+                dma.ch8.into(), dma.ch9.into(), dma.ch10.into(), dma.ch11.into(),
+            ];
+
             let pio0 = Pio::new(pac.PIO0, &mut pac.RESETS);
             let pio1 = Pio::new(pac.PIO1, &mut pac.RESETS);
 
             unsafe {
                 SINGLETON = Some(Hardware {
                     delay: RefCell::new(Some(delay)),
+                    dma_channels: RefCell::new(dma_channels),
                     pin0: RefCell::new(Some(pins.gpio0)),
                     pin1: RefCell::new(Some(pins.gpio1)),
                     pin2: RefCell::new(Some(pins.gpio2)),
@@ -145,6 +165,8 @@ impl Hardware {
                     pio1: RefCell::new(Some(pio1)),
                     usb: RefCell::new(None),
                     usb_bus,
+                    timer,
+                    system_clock_hz,
                 });
 
                 usb = UsbManager::new(&SINGLETON.as_ref().unwrap().usb_bus);
@@ -163,6 +185,18 @@ impl Hardware {
     // Getters and setters
     ////////////////////////////////////////////////////////////////////////////
 
+    /// The frequency the system clock actually ended up running at
+    pub fn system_clock_hz(&self) -> u32 {
+        self.system_clock_hz
+    }
+
+    /// The 1MHz system timer, for measuring timeouts
+    ///
+    /// Every copy reads the same counter, so this never needs to be returned.
+    pub fn timer(&self) -> Timer {
+        self.timer
+    }
+
     pub fn get_delay_mut(&mut self) -> Option<&mut Delay> {
         self.delay.get_mut().as_mut()
     }
@@ -180,6 +214,99 @@ impl Hardware {
         Ok(())
     }
 
+    /// Takes any free DMA channel
+    pub fn take_dma_channel(&mut self) -> Option<DmaChannel> {
+        self.dma_channels.get_mut().pop()
+    }
+
+    pub fn return_dma_channel(&mut self, channel: DmaChannel) -> Result<(), Error> {
+        let channels = self.dma_channels.get_mut();
+
+        if channels.iter().any(|owned| owned.id() == channel.id()) {
+            return Err(Error::AttemptToReturnExistingValue);
+        }
+
+        channels.push(channel);
+        Ok(())
+    }
+
+    /// Takes a pin by its GPIO number
+    ///
+    /// Returns `None` if the pin has already been taken, or there is no such
+    /// pin.
+    pub fn take_pin(&mut self, number: u8) -> Option<DynPin> {
+        match number {
+            0 => self.take_pin0().map(|pin| pin.into_dyn_pin()),
+            1 => self.take_pin1().map(|pin| pin.into_dyn_pin()),
+            2 => self.take_pin2().map(|pin| pin.into_dyn_pin()),
+            3 => self.take_pin3().map(|pin| pin.into_dyn_pin()),
+            4 => self.take_pin4().map(|pin| pin.into_dyn_pin()),
+            5 => self.take_pin5().map(|pin| pin.into_dyn_pin()),
+            6 => self.take_pin6().map(|pin| pin.into_dyn_pin()),
+            7 => self.take_pin7().map(|pin| pin.into_dyn_pin()),
+            8 => self.take_pin8().map(|pin| pin.into_dyn_pin()),
+            9 => self.take_pin9().map(|pin| pin.into_dyn_pin()),
+            10 => self.take_pin10().map(|pin| pin.into_dyn_pin()),
+            11 => self.take_pin11().map(|pin| pin.into_dyn_pin()),
+            12 => self.take_pin12().map(|pin| pin.into_dyn_pin()),
+            13 => self.take_pin13().map(|pin| pin.into_dyn_pin()),
+            14 => self.take_pin14().map(|pin| pin.into_dyn_pin()),
+            15 => self.take_pin15().map(|pin| pin.into_dyn_pin()),
+            16 => self.take_pin16().map(|pin| pin.into_dyn_pin()),
+            17 => self.take_pin17().map(|pin| pin.into_dyn_pin()),
+            18 => self.take_pin18().map(|pin| pin.into_dyn_pin()),
+            19 => self.take_pin19().map(|pin| pin.into_dyn_pin()),
+            20 => self.take_pin20().map(|pin| pin.into_dyn_pin()),
+            21 => self.take_pin21().map(|pin| pin.into_dyn_pin()),
+            22 => self.take_pin22().map(|pin| pin.into_dyn_pin()),
+            23 => self.take_pin23().map(|pin| pin.into_dyn_pin()),
+            24 => self.take_pin24().map(|pin| pin.into_dyn_pin()),
+            25 => self.take_pin25().map(|pin| pin.into_dyn_pin()),
+            26 => self.take_pin26().map(|pin| pin.into_dyn_pin()),
+            27 => self.take_pin27().map(|pin| pin.into_dyn_pin()),
+            28 => self.take_pin28().map(|pin| pin.into_dyn_pin()),
+            29 => self.take_pin29().map(|pin| pin.into_dyn_pin()),
+            _ => None,
+        }
+    }
+
+    /// Gives back a pin taken with `take_pin`
+    pub fn return_pin(&mut self, pin: DynPin) -> Result<(), Error> {
+        match pin.id().num {
+            0 => self.return_pin0(pin.try_into_pin().map_err(|_| Error::InvalidPin)?),
+            1 => self.return_pin1(pin.try_into_pin().map_err(|_| Error::InvalidPin)?),
+            2 => self.return_pin2(pin.try_into_pin().map_err(|_| Error::InvalidPin)?),
+            3 => self.return_pin3(pin.try_into_pin().map_err(|_| Error::InvalidPin)?),
+            4 => self.return_pin4(pin.try_into_pin().map_err(|_| Error::InvalidPin)?),
+            5 => self.return_pin5(pin.try_into_pin().map_err(|_| Error::InvalidPin)?),
+            6 => self.return_pin6(pin.try_into_pin().map_err(|_| Error::InvalidPin)?),
+            7 => self.return_pin7(pin.try_into_pin().map_err(|_| Error::InvalidPin)?),
+            8 => self.return_pin8(pin.try_into_pin().map_err(|_| Error::InvalidPin)?),
+            9 => self.return_pin9(pin.try_into_pin().map_err(|_| Error::InvalidPin)?),
+            10 => self.return_pin10(pin.try_into_pin().map_err(|_| Error::InvalidPin)?),
+            11 => self.return_pin11(pin.try_into_pin().map_err(|_| Error::InvalidPin)?),
+            12 => self.return_pin12(pin.try_into_pin().map_err(|_| Error::InvalidPin)?),
+            13 => self.return_pin13(pin.try_into_pin().map_err(|_| Error::InvalidPin)?),
+            14 => self.return_pin14(pin.try_into_pin().map_err(|_| Error::InvalidPin)?),
+            15 => self.return_pin15(pin.try_into_pin().map_err(|_| Error::InvalidPin)?),
+            16 => self.return_pin16(pin.try_into_pin().map_err(|_| Error::InvalidPin)?),
+            17 => self.return_pin17(pin.try_into_pin().map_err(|_| Error::InvalidPin)?),
+            18 => self.return_pin18(pin.try_into_pin().map_err(|_| Error::InvalidPin)?),
+            19 => self.return_pin19(pin.try_into_pin().map_err(|_| Error::InvalidPin)?),
+            20 => self.return_pin20(pin.try_into_pin().map_err(|_| Error::InvalidPin)?),
+            21 => self.return_pin21(pin.try_into_pin().map_err(|_| Error::InvalidPin)?),
+            22 => self.return_pin22(pin.try_into_pin().map_err(|_| Error::InvalidPin)?),
+            23 => self.return_pin23(pin.try_into_pin().map_err(|_| Error::InvalidPin)?),
+            24 => self.return_pin24(pin.try_into_pin().map_err(|_| Error::InvalidPin)?),
+            25 => self.return_pin25(pin.try_into_pin().map_err(|_| Error::InvalidPin)?),
+            26 => self.return_pin26(pin.try_into_pin().map_err(|_| Error::InvalidPin)?),
+            27 => self.return_pin27(pin.try_into_pin().map_err(|_| Error::InvalidPin)?),
+            28 => self.return_pin28(pin.try_into_pin().map_err(|_| Error::InvalidPin)?),
+            29 => self.return_pin29(pin.try_into_pin().map_err(|_| Error::InvalidPin)?),
+            _ => Err(Error::InvalidPin),
+        }
+    }
+
     pub fn take_pin0(&mut self) -> Option<P<Gpio0>> {
         self.pin0.replace(None)
     }
@@ -605,6 +732,11 @@ impl Hardware {
         Ok(())
     }
 
+    /// Borrows both PIO blocks at once, for working with them together
+    pub fn get_pios_mut(&mut self) -> (Option<&mut Pio<PIO0>>, Option<&mut Pio<PIO1>>) {
+        (self.pio0.get_mut().as_mut(), self.pio1.get_mut().as_mut())
+    }
+
     pub fn get_usb_mut(&mut self) -> Option<&mut UsbManager> {
         self.usb.get_mut().as_mut()
     }
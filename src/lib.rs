@@ -0,0 +1,50 @@
+//! Drives WS2812B style LED strips from the RP2040's PIO blocks
+//!
+//! The default `rp2040` feature builds for the board. The `host` feature swaps
+//! the hardware for the mocks in `mock` instead, so everything built on top of
+//! it can be tested on the build machine with `cargo host-test`. It also
+//! brings in `strip_sim`, for trying out effects without a board, and
+//! `pio_sim` and `waveform`, for checking programs without a logic analyzer.
+
+#![no_std]
+
+#[cfg(not(any(feature = "rp2040", feature = "host")))]
+compile_error!("enable the `rp2040` feature to build for the board, or `host` to build against the mocks");
+
+extern crate alloc;
+#[cfg(feature = "host")]
+extern crate std;
+
+pub mod chipset;
+pub mod clock_divisor;
+pub mod color;
+pub mod correction;
+pub mod dither;
+#[cfg_attr(feature = "host", path = "mock/dma.rs")]
+pub mod dma;
+pub mod effect;
+pub mod fifo;
+pub mod hal;
+#[cfg_attr(feature = "host", path = "mock/hardware.rs")]
+pub mod hardware;
+#[cfg(feature = "host")]
+pub mod mock;
+pub(crate) mod output;
+pub mod output_manager;
+pub mod parallel;
+pub mod pio;
+#[cfg(any(test, feature = "host"))]
+pub mod pio_sim;
+pub mod tx;
+pub mod tx_queue;
+pub mod rx;
+pub mod serial_logger;
+pub mod state_machine;
+pub mod strip;
+#[cfg(feature = "host")]
+pub mod strip_sim;
+#[cfg_attr(feature = "host", path = "mock/usb_manager.rs")]
+pub mod usb_manager;
+#[cfg(any(test, feature = "host"))]
+pub mod waveform;
+pub mod ws2812b;
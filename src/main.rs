@@ -8,23 +8,14 @@ static BOOT_LOADER: [u8; 256] = rp2040_boot2::BOOT_LOADER_GENERIC_03H;
 #[cfg(feature = "rt")]
 extern crate cortex_m_rt;
 
-extern crate alloc;
-
-pub mod hardware;
-pub mod pio;
-pub mod tx;
-pub mod rx;
-pub mod serial_logger;
-pub mod state_machine;
-pub mod usb_manager;
-
 use core::mem::MaybeUninit;
 use embedded_alloc::Heap;
-use hardware::Hardware;
 use log::info;
 use panic_reset as _;
 use rp2040_hal::entry;
-use serial_logger::SerialLogger;
+use rp2040_hal::pac::interrupt;
+use ws2812b::hardware::Hardware;
+use ws2812b::serial_logger::SerialLogger;
 
 #[global_allocator]
 static HEAP: Heap = Heap::empty();
@@ -49,6 +40,24 @@ fn main() -> ! {
     }
 }
 
+#[allow(non_snake_case)]
+#[interrupt]
+fn DMA_IRQ_0() {
+    unsafe { ws2812b::dma::on_irq() }
+}
+
+#[allow(non_snake_case)]
+#[interrupt]
+fn PIO0_IRQ_0() {
+    unsafe { ws2812b::tx_queue::drain_block(0) }
+}
+
+#[allow(non_snake_case)]
+#[interrupt]
+fn PIO1_IRQ_0() {
+    unsafe { ws2812b::tx_queue::drain_block(1) }
+}
+
 fn init_allocator() {
     const HEAP_SIZE: usize = 128 * 1024;
     static mut HEAP_MEM: [MaybeUninit<u8>; HEAP_SIZE] = [MaybeUninit::uninit(); HEAP_SIZE];
@@ -0,0 +1,64 @@
+//! DMA channels that finish the moment they start
+//!
+//! `start` hands every word straight to the mock tx FIFO, where it can be
+//! picked up with `mock::pio::take_sent`, then runs the callback as if the
+//! interrupt had fired.
+
+use crate::mock::pio;
+use crate::tx::Tx;
+
+/// Number of DMA channels on the RP2040
+pub const NUM_CHANNELS: usize = 12;
+
+/// A single DMA channel
+pub struct DmaChannel {
+    id: u8,
+    callback: Option<fn()>,
+}
+
+impl DmaChannel {
+    pub fn new(id: u8) -> DmaChannel {
+        DmaChannel {id, callback: None}
+    }
+
+    /// The index of this channel
+    pub fn id(&self) -> u8 {
+        self.id
+    }
+
+    /// Writes a buffer into a tx FIFO
+    ///
+    /// # Safety
+    /// Always safe on the host, this only matches the real channel
+    pub unsafe fn start(&mut self, words: &[u32], tx: &Tx) {
+        pio::extend_sent(tx.block() as usize, tx.index() as usize, words);
+
+        if let Some(callback) = self.callback {
+            callback();
+        }
+    }
+
+    /// Transfers finish as soon as they start, so this is always false
+    pub fn is_busy(&self) -> bool {
+        false
+    }
+
+    pub fn is_done(&self) -> bool {
+        !self.is_busy()
+    }
+
+    pub fn wait(&self) {}
+
+    pub fn abort(&mut self) {}
+
+    /// Sets a function to call whenever a transfer on this channel finishes
+    pub fn set_callback(&mut self, callback: Option<fn()>) {
+        self.callback = callback;
+    }
+}
+
+/// Callbacks run as soon as a transfer starts on the host, so this does nothing
+///
+/// # Safety
+/// Always safe on the host, this only matches the real one
+pub unsafe fn on_irq() {}
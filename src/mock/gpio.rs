@@ -0,0 +1,126 @@
+//! Pins that remember what they've been set to
+//!
+//! Only pins with a runtime id exist here, since that's all the library
+//! passes around. The function, drive strength, and slew rate can be read
+//! back to check what was done to a pin.
+
+use core::marker::PhantomData;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DynPinId {
+    pub num: u8,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DynFunction {
+    Pio0,
+    Pio1,
+    Null,
+}
+
+pub struct FunctionNull;
+
+pub struct PullDown;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OutputDriveStrength {
+    TwoMilliAmps,
+    FourMilliAmps,
+    EightMilliAmps,
+    TwelveMilliAmps,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OutputSlewRate {
+    Slow,
+    Fast,
+}
+
+pub struct InvalidFunction;
+
+/// A function type a pin can be converted to
+pub trait Function {
+    /// What the pin's function becomes, given what it was
+    fn convert(current: DynFunction) -> DynFunction;
+}
+
+impl Function for FunctionNull {
+    fn convert(_current: DynFunction) -> DynFunction {
+        DynFunction::Null
+    }
+}
+
+impl Function for DynFunction {
+    fn convert(current: DynFunction) -> DynFunction {
+        current
+    }
+}
+
+pub struct Pin<I, F, M> {
+    id: DynPinId,
+    function: DynFunction,
+    drive_strength: OutputDriveStrength,
+    slew_rate: OutputSlewRate,
+    _marker: PhantomData<(I, F, M)>,
+}
+
+impl Pin<DynPinId, FunctionNull, PullDown> {
+    /// A pin straight out of reset
+    pub fn new(num: u8) -> Self {
+        Pin {
+            id: DynPinId {num},
+            function: DynFunction::Null,
+            drive_strength: OutputDriveStrength::FourMilliAmps,
+            slew_rate: OutputSlewRate::Slow,
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<F: Function, M> Pin<DynPinId, F, M> {
+    pub fn id(&self) -> DynPinId {
+        self.id
+    }
+
+    pub fn function(&self) -> DynFunction {
+        self.function
+    }
+
+    pub fn drive_strength(&self) -> OutputDriveStrength {
+        self.drive_strength
+    }
+
+    pub fn slew_rate(&self) -> OutputSlewRate {
+        self.slew_rate
+    }
+
+    pub fn set_drive_strength(&mut self, drive_strength: OutputDriveStrength) {
+        self.drive_strength = drive_strength;
+    }
+
+    pub fn set_slew_rate(&mut self, slew_rate: OutputSlewRate) {
+        self.slew_rate = slew_rate;
+    }
+
+    pub fn into_function<F2: Function>(self) -> Pin<DynPinId, F2, M> {
+        Pin {
+            id: self.id,
+            function: F2::convert(self.function),
+            drive_strength: self.drive_strength,
+            slew_rate: self.slew_rate,
+            _marker: PhantomData,
+        }
+    }
+
+    /// Every function exists on every pin here, so this never fails
+    pub fn try_into_function<F2: Function>(self) -> Result<Pin<DynPinId, F2, M>, Self> {
+        Ok(self.into_function())
+    }
+}
+
+impl<M> Pin<DynPinId, DynFunction, M> {
+    pub fn try_set_function(&mut self, function: DynFunction) -> Result<(), InvalidFunction> {
+        self.function = function;
+        Ok(())
+    }
+}
@@ -0,0 +1,206 @@
+//! Stands in for the hardware singleton on the host
+//!
+//! The API matches the real `Hardware`, except that pins can only be taken by
+//! number, since that's all the library uses. The singleton is per thread, so
+//! each test gets its own.
+
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::cell::{Cell, RefCell};
+
+use crate::dma::{DmaChannel, NUM_CHANNELS};
+use crate::hal::gpio::{DynPinId, FunctionNull, Pin, PullDown};
+use crate::hal::pac::{PIO0, PIO1, RESETS};
+use crate::hal::{Delay, Timer};
+use crate::pio::Pio;
+use crate::usb_manager::UsbManager;
+
+std::thread_local! {
+    static SINGLETON: Cell<*mut Hardware> = const { Cell::new(core::ptr::null_mut()) };
+}
+
+type OptCell<T> = RefCell<Option<T>>;
+
+/// A pin whose number is only known at runtime
+pub type DynPin = Pin<DynPinId, FunctionNull, PullDown>;
+
+/// Number of GPIO pins in bank 0
+const NUM_PINS: u8 = 30;
+
+/// The system clock the real board ends up running at
+const SYSTEM_CLOCK_HZ: u32 = 125_000_000;
+
+#[derive(Debug)]
+pub enum Error {
+    AttemptToReturnExistingValue,
+    /// There is no pin with that number
+    InvalidPin,
+}
+
+pub struct Hardware {
+    delay: OptCell<Delay>,
+    dma_channels: RefCell<Vec<DmaChannel>>,
+    pins: Vec<OptCell<DynPin>>,
+    pio0: OptCell<Pio<PIO0>>,
+    pio1: OptCell<Pio<PIO1>>,
+    usb: OptCell<UsbManager>,
+    timer: Timer,
+    system_clock_hz: u32,
+}
+
+impl Hardware {
+    /// Sets up fresh mock hardware for this thread
+    ///
+    /// The crystal frequency is ignored, the system clock always ends up at
+    /// 125MHz like on the board. Calling this again starts over, leaking the
+    /// old hardware.
+    pub fn init(_crystal_frequency: u32) {
+        let mut resets = RESETS;
+
+        let hardware = Hardware {
+            delay: RefCell::new(Some(Delay::new())),
+            dma_channels: RefCell::new((0..NUM_CHANNELS as u8).map(DmaChannel::new).collect()),
+            pins: (0..NUM_PINS).map(|num| RefCell::new(Some(Pin::new(num)))).collect(),
+            pio0: RefCell::new(Some(Pio::new(PIO0, &mut resets))),
+            pio1: RefCell::new(Some(Pio::new(PIO1, &mut resets))),
+            usb: RefCell::new(Some(UsbManager::new())),
+            timer: Timer::new(),
+            system_clock_hz: SYSTEM_CLOCK_HZ,
+        };
+
+        SINGLETON.with(|singleton| singleton.set(Box::leak(Box::new(hardware))));
+    }
+
+    /// Get this thread's hardware singleton
+    pub fn get() -> Option<&'static mut Self> {
+        SINGLETON.with(|singleton| unsafe { singleton.get().as_mut() })
+    }
+
+    ////////////////////////////////////////////////////////////////////////////
+    // Getters and setters
+    ////////////////////////////////////////////////////////////////////////////
+
+    /// The frequency the system clock actually ended up running at
+    pub fn system_clock_hz(&self) -> u32 {
+        self.system_clock_hz
+    }
+
+    /// The mock system timer, see `mock::timer`
+    pub fn timer(&self) -> Timer {
+        self.timer
+    }
+
+    pub fn get_delay_mut(&mut self) -> Option<&mut Delay> {
+        self.delay.get_mut().as_mut()
+    }
+
+    pub fn take_delay(&mut self) -> Option<Delay> {
+        self.delay.replace(None)
+    }
+
+    pub fn return_delay(&mut self, delay: Delay) -> Result<(), Error> {
+        if already_owned(&self.delay) {
+            return Err(Error::AttemptToReturnExistingValue);
+        }
+
+        self.delay.replace(Some(delay));
+        Ok(())
+    }
+
+    /// Takes any free DMA channel
+    pub fn take_dma_channel(&mut self) -> Option<DmaChannel> {
+        self.dma_channels.get_mut().pop()
+    }
+
+    pub fn return_dma_channel(&mut self, channel: DmaChannel) -> Result<(), Error> {
+        let channels = self.dma_channels.get_mut();
+
+        if channels.iter().any(|owned| owned.id() == channel.id()) {
+            return Err(Error::AttemptToReturnExistingValue);
+        }
+
+        channels.push(channel);
+        Ok(())
+    }
+
+    /// Takes a pin by its GPIO number
+    ///
+    /// Returns `None` if the pin has already been taken, or there is no such
+    /// pin.
+    pub fn take_pin(&mut self, number: u8) -> Option<DynPin> {
+        self.pins.get(number as usize)?.replace(None)
+    }
+
+    /// Gives back a pin taken with `take_pin`
+    pub fn return_pin(&mut self, pin: DynPin) -> Result<(), Error> {
+        let slot = self.pins.get(pin.id().num as usize).ok_or(Error::InvalidPin)?;
+
+        if already_owned(slot) {
+            return Err(Error::AttemptToReturnExistingValue);
+        }
+
+        slot.replace(Some(pin));
+        Ok(())
+    }
+
+    pub fn get_pio0_mut(&mut self) -> Option<&mut Pio<PIO0>> {
+        self.pio0.get_mut().as_mut()
+    }
+
+    pub fn take_pio0(&mut self) -> Option<Pio<PIO0>> {
+        self.pio0.replace(None)
+    }
+
+    pub fn return_pio0(&mut self, pio0: Pio<PIO0>) -> Result<(), Error> {
+        if already_owned(&self.pio0) {
+            return Err(Error::AttemptToReturnExistingValue);
+        }
+
+        self.pio0.replace(Some(pio0));
+        Ok(())
+    }
+
+    pub fn get_pio1_mut(&mut self) -> Option<&mut Pio<PIO1>> {
+        self.pio1.get_mut().as_mut()
+    }
+
+    pub fn take_pio1(&mut self) -> Option<Pio<PIO1>> {
+        self.pio1.replace(None)
+    }
+
+    pub fn return_pio1(&mut self, pio1: Pio<PIO1>) -> Result<(), Error> {
+        if already_owned(&self.pio1) {
+            return Err(Error::AttemptToReturnExistingValue);
+        }
+
+        self.pio1.replace(Some(pio1));
+        Ok(())
+    }
+
+    /// Borrows both PIO blocks at once, for working with them together
+    pub fn get_pios_mut(&mut self) -> (Option<&mut Pio<PIO0>>, Option<&mut Pio<PIO1>>) {
+        (self.pio0.get_mut().as_mut(), self.pio1.get_mut().as_mut())
+    }
+
+    pub fn get_usb_mut(&mut self) -> Option<&mut UsbManager> {
+        self.usb.get_mut().as_mut()
+    }
+
+    pub fn take_usb(&mut self) -> Option<UsbManager> {
+        self.usb.replace(None)
+    }
+
+    pub fn return_usb(&mut self, usb: UsbManager) -> Result<(), Error> {
+        if already_owned(&self.usb) {
+            return Err(Error::AttemptToReturnExistingValue);
+        }
+
+        self.usb.replace(Some(usb));
+        Ok(())
+    }
+}
+
+fn already_owned<T>(data: &OptCell<T>) -> bool {
+    let result = data.try_borrow();
+    !matches!(result, Ok(v) if v.as_ref().is_none())
+}
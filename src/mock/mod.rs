@@ -0,0 +1,31 @@
+//! Stand-ins for the hardware, for testing on the host
+//!
+//! `gpio`, `pac`, `pio`, `delay`, and `timer` replace the parts of rp2040_hal
+//! and cortex_m re-exported from `hal`. `dma.rs`, `hardware.rs`, and
+//! `usb_manager.rs` in here replace the crate's own modules of the same name
+//! outright, see lib.rs.
+//!
+//! Every bit of mocked state is kept per thread. Each test runs on its own
+//! thread, so tests can't see each other's hardware even when run in
+//! parallel. Call `Hardware::init` at the start of each one.
+
+pub mod delay;
+pub mod gpio;
+pub mod pac;
+pub mod pio;
+pub mod timer;
+
+/// The rx and tx of PIO0 SM0, with the parallel program installed
+#[cfg(test)]
+pub(crate) fn rx_tx() -> (crate::rx::Rx, crate::tx::Tx) {
+    use crate::clock_divisor::ClockDivisor;
+    use crate::hardware::Hardware;
+    use crate::parallel;
+    use crate::state_machine::StateMachineConfig;
+
+    let pio = Hardware::get().unwrap().get_pio0_mut().unwrap();
+    let config = StateMachineConfig::new(ClockDivisor::new(1, 0));
+    let mut rxtxs = pio.install_program(parallel::program(), [config]).unwrap();
+
+    rxtxs.pop().unwrap().split()
+}
@@ -0,0 +1,130 @@
+//! The few peripherals the library reaches into directly
+//!
+//! Each PIO block gets a register block with just CTRL and FLEVEL, backed by
+//! plain memory. Writing CTRL keeps the enable bits and drops the self
+//! clearing restart bits, like the hardware does.
+
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use std::boxed::Box;
+
+std::thread_local! {
+    static BLOCKS: &'static [pio0::RegisterBlock; 2] = Box::leak(Box::new([
+        pio0::RegisterBlock::new(),
+        pio0::RegisterBlock::new(),
+    ]));
+}
+
+pub struct RESETS;
+
+pub struct PIO0;
+
+pub struct PIO1;
+
+impl PIO0 {
+    pub fn ptr() -> *const pio0::RegisterBlock {
+        BLOCKS.with(|blocks| &blocks[0] as *const _)
+    }
+}
+
+impl PIO1 {
+    pub fn ptr() -> *const pio0::RegisterBlock {
+        BLOCKS.with(|blocks| &blocks[1] as *const _)
+    }
+}
+
+pub mod pio0 {
+    use super::*;
+
+    pub struct RegisterBlock {
+        ctrl: Register,
+        flevel: Register,
+    }
+
+    impl RegisterBlock {
+        pub(super) const fn new() -> RegisterBlock {
+            RegisterBlock {ctrl: Register::new(0xf), flevel: Register::new(0)}
+        }
+
+        pub fn ctrl(&self) -> &Register {
+            &self.ctrl
+        }
+
+        pub fn flevel(&self) -> &Register {
+            &self.flevel
+        }
+    }
+}
+
+/// A register that keeps the bits in `mask` of whatever is written to it
+pub struct Register {
+    bits: AtomicU32,
+    mask: u32,
+}
+
+pub struct R {
+    bits: u32,
+}
+
+pub struct W {
+    bits: u32,
+}
+
+impl Register {
+    const fn new(mask: u32) -> Register {
+        Register {bits: AtomicU32::new(0), mask}
+    }
+
+    pub fn read(&self) -> R {
+        R {bits: self.bits.load(Ordering::Relaxed)}
+    }
+
+    pub fn write(&self, f: impl FnOnce(&mut W) -> &mut W) {
+        let mut w = W {bits: 0};
+        f(&mut w);
+
+        self.bits.store(w.bits & self.mask, Ordering::Relaxed);
+    }
+
+    pub fn modify(&self, f: impl for<'w> FnOnce(&R, &'w mut W) -> &'w mut W) {
+        let r = self.read();
+        let mut w = W {bits: r.bits};
+        f(&r, &mut w);
+
+        self.bits.store(w.bits & self.mask, Ordering::Relaxed);
+    }
+}
+
+impl R {
+    pub fn bits(&self) -> u32 {
+        self.bits
+    }
+}
+
+impl W {
+    /// # Safety
+    /// Always safe on the host, this only matches the PAC
+    pub unsafe fn bits(&mut self, bits: u32) -> &mut W {
+        self.bits = bits;
+        self
+    }
+}
+
+#[allow(non_camel_case_types)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Interrupt {
+    DMA_IRQ_0,
+    PIO0_IRQ_0,
+    PIO1_IRQ_0,
+    USBCTRL_IRQ,
+}
+
+pub struct NVIC;
+
+impl NVIC {
+    /// There are no interrupts on the host, so this does nothing
+    ///
+    /// # Safety
+    /// Always safe on the host, this only matches the PAC
+    pub unsafe fn unmask(_interrupt: Interrupt) {}
+}
@@ -0,0 +1,588 @@
+//! PIO blocks that keep track of what they were told to do
+//!
+//! Instruction memory is allocated the same way as on the hardware, and every
+//! state machine records the config it was built with. Words written to a tx
+//! FIFO are kept for `take_sent`, and count towards its level until the state
+//! machine pulls them. It pulls one word each time the FIFO is checked for
+//! being empty or stalled, so anything waiting on it really has to spin, and
+//! it stalls once it checks with nothing left. The FIFOs never fill up unless
+//! `set_tx_room` says so, words past their depth count as already pulled.
+
+use core::cell::RefCell;
+use core::marker::PhantomData;
+
+use ::pio::{Program, SideSet, Wrap, RP2040_MAX_PROGRAM_SIZE};
+use std::collections::VecDeque;
+use std::vec::Vec;
+
+use super::pac::{self, PIO0, PIO1, RESETS};
+
+/// Words a tx FIFO holds
+const TX_DEPTH: usize = 4;
+
+std::thread_local! {
+    static STATE_MACHINES: RefCell<[Record; 8]> = RefCell::new(Default::default());
+}
+
+/// Everything known about one state machine, see `record`
+#[derive(Clone, Debug, Default)]
+pub struct Record {
+    /// The config it was last built with, while it's initialized
+    pub config: Option<BuiltConfig>,
+    /// Bit n is set if pin n was switched to an output
+    pub pindirs: u32,
+    /// Every word written to the tx FIFO, in order
+    pub sent: Vec<u32>,
+    /// Words waiting to be read from the rx FIFO
+    pub received: VecDeque<u32>,
+    /// How many more words the tx FIFO takes before it's full, or `None` if it
+    /// never fills up. See `set_tx_room`.
+    pub tx_room: Option<usize>,
+    /// Words in the tx FIFO that the state machine hasn't pulled yet
+    pub tx_level: usize,
+    /// Whether the state machine has stalled on an empty tx FIFO since the
+    /// flag was last cleared
+    pub tx_stalled: bool,
+}
+
+impl Record {
+    fn fill(&mut self, words: &[u32]) {
+        self.sent.extend_from_slice(words);
+        self.tx_level = (self.tx_level + words.len()).min(TX_DEPTH);
+    }
+
+    /// Has the state machine pull a word, or stall if there isn't one
+    fn pull(&mut self) {
+        match self.tx_level {
+            0 => self.tx_stalled = true,
+            _ => self.tx_level -= 1,
+        }
+    }
+}
+
+/// What a state machine was built with, with absolute addresses
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BuiltConfig {
+    pub offset: u8,
+    pub wrap: Wrap,
+    pub side_set: (bool, u8, bool),
+    pub set_pins: (u8, u8),
+    pub out_pins: (u8, u8),
+    pub side_set_base: u8,
+    pub in_pin_base: u8,
+    pub jmp_pin: u8,
+    pub autopull: bool,
+    pub pull_threshold: u8,
+    pub out_shift_direction: ShiftDirection,
+    pub autopush: bool,
+    pub push_threshold: u8,
+    pub in_shift_direction: ShiftDirection,
+    pub buffers: Buffers,
+    pub clock_divisor: (u16, u8),
+}
+
+/// A copy of what's known about a state machine
+pub fn record(block: usize, index: usize) -> Record {
+    with_record(block, index, |record| record.clone())
+}
+
+/// Takes every word written to a state machine's tx FIFO so far
+pub fn take_sent(block: usize, index: usize) -> Vec<u32> {
+    with_record(block, index, |record| core::mem::take(&mut record.sent))
+}
+
+/// Records words as written to a state machine's tx FIFO, for the mock DMA
+pub(crate) fn extend_sent(block: usize, index: usize, words: &[u32]) {
+    with_record(block, index, |record| record.fill(words));
+}
+
+/// Queues a word for the state machine's rx FIFO to hand back
+pub fn push_received(block: usize, index: usize, word: u32) {
+    with_record(block, index, |record| record.received.push_back(word));
+}
+
+/// Makes a state machine's tx FIFO fill up after taking `room` more words, as
+/// if it had stopped pulling, or never fill up with `None`
+pub fn set_tx_room(block: usize, index: usize, room: Option<usize>) {
+    with_record(block, index, |record| record.tx_room = room);
+}
+
+/// Makes a state machine's tx FIFO refuse words, or take them all again
+pub fn set_tx_full(block: usize, index: usize, full: bool) {
+    set_tx_room(block, index, full.then_some(0));
+}
+
+/// Bit mask of the state machines enabled in a block's CTRL register
+pub fn enabled_mask(block: usize) -> u8 {
+    block_registers(block).ctrl().read().bits() as u8 & 0xf
+}
+
+fn with_record<T>(block: usize, index: usize, f: impl FnOnce(&mut Record) -> T) -> T {
+    STATE_MACHINES.with(|records| f(&mut records.borrow_mut()[block * 4 + index]))
+}
+
+fn block_registers(block: usize) -> &'static pac::pio0::RegisterBlock {
+    unsafe {
+        match block {
+            0 => &*PIO0::ptr(),
+            _ => &*PIO1::ptr(),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ShiftDirection {
+    Left,
+    Right,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Buffers {
+    RxTx,
+    OnlyTx,
+    OnlyRx,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PinDir {
+    Input,
+    Output,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PioIRQ {
+    Irq0,
+    Irq1,
+}
+
+#[derive(Debug)]
+pub enum InstallError {
+    NoSpace,
+}
+
+/// A block and its four state machines, as returned by `PIOExt::split`
+pub type Split<P> = (
+    PIO<P>,
+    UninitStateMachine<(P, SM0)>,
+    UninitStateMachine<(P, SM1)>,
+    UninitStateMachine<(P, SM2)>,
+    UninitStateMachine<(P, SM3)>,
+);
+
+/// A stopped state machine and its FIFOs, as returned by `PIOBuilder::build`
+pub type Built<SM> = (StateMachine<SM, Stopped>, Rx<SM>, Tx<SM>);
+
+pub trait PIOExt: Sized + 'static {
+    fn id() -> usize;
+
+    fn split(self, _resets: &mut RESETS) -> Split<Self> {
+        (
+            PIO {used: 0, _pio: PhantomData},
+            UninitStateMachine {_sm: PhantomData},
+            UninitStateMachine {_sm: PhantomData},
+            UninitStateMachine {_sm: PhantomData},
+            UninitStateMachine {_sm: PhantomData},
+        )
+    }
+}
+
+impl PIOExt for PIO0 {
+    fn id() -> usize {
+        0
+    }
+}
+
+impl PIOExt for PIO1 {
+    fn id() -> usize {
+        1
+    }
+}
+
+pub trait StateMachineIndex: 'static {
+    fn id() -> usize;
+}
+
+pub enum SM0 {}
+pub enum SM1 {}
+pub enum SM2 {}
+pub enum SM3 {}
+
+impl StateMachineIndex for SM0 {
+    fn id() -> usize {0}
+}
+
+impl StateMachineIndex for SM1 {
+    fn id() -> usize {1}
+}
+
+impl StateMachineIndex for SM2 {
+    fn id() -> usize {2}
+}
+
+impl StateMachineIndex for SM3 {
+    fn id() -> usize {3}
+}
+
+/// A block and state machine index pair
+pub trait ValidStateMachine {
+    type PIO: PIOExt;
+
+    fn block() -> usize {
+        Self::PIO::id()
+    }
+
+    fn index() -> usize;
+}
+
+impl<P: PIOExt, SM: StateMachineIndex> ValidStateMachine for (P, SM) {
+    type PIO = P;
+
+    fn index() -> usize {
+        SM::id()
+    }
+}
+
+pub struct PIO<P: PIOExt> {
+    /// Bit n is set while instruction n is in use
+    used: u32,
+    _pio: PhantomData<P>,
+}
+
+impl<P: PIOExt> PIO<P> {
+    /// Finds room for a program, from the top of instruction memory down like
+    /// the HAL does
+    pub fn install(&mut self, program: &Program<RP2040_MAX_PROGRAM_SIZE>) -> Result<InstalledProgram<P>, InstallError> {
+        let length = program.code.len() as u32;
+        let mask = 1u32.checked_shl(length).map_or(u32::MAX, |bit| bit - 1);
+
+        let fits = |offset: u32| offset + length <= 32 && self.used & mask << offset == 0;
+
+        let offset = match program.origin {
+            Some(origin) => fits(origin as u32).then_some(origin as u32),
+            None => (0..=32 - length).rev().find(|&offset| fits(offset)),
+        };
+
+        let offset = offset.ok_or(InstallError::NoSpace)?;
+        self.used |= mask << offset;
+
+        Ok(InstalledProgram {
+            offset: offset as u8,
+            length: length as u8,
+            wrap: Wrap {
+                source: program.wrap.source + offset as u8,
+                target: program.wrap.target + offset as u8,
+            },
+            side_set: program.side_set,
+            _pio: PhantomData,
+        })
+    }
+
+    pub fn uninstall(&mut self, program: InstalledProgram<P>) {
+        let mask = 1u32.checked_shl(program.length as u32).map_or(u32::MAX, |bit| bit - 1);
+        self.used &= !(mask << program.offset);
+    }
+
+    /// Bit n is set while instruction n is in use
+    pub fn used_instructions(&self) -> u32 {
+        self.used
+    }
+}
+
+#[derive(Debug)]
+pub struct InstalledProgram<P> {
+    offset: u8,
+    length: u8,
+    /// Absolute addresses
+    wrap: Wrap,
+    side_set: SideSet,
+    _pio: PhantomData<P>,
+}
+
+impl<P: PIOExt> InstalledProgram<P> {
+    pub fn offset(&self) -> u8 {
+        self.offset
+    }
+
+    pub fn wrap_target(&self) -> u8 {
+        self.wrap.target
+    }
+
+    /// # Safety
+    /// Always safe on the host, this only matches the HAL
+    pub unsafe fn share(&self) -> InstalledProgram<P> {
+        InstalledProgram {_pio: PhantomData, ..*self}
+    }
+
+    /// Changes the wrap, relative to the start of the program
+    pub fn set_wrap(self, wrap: Wrap) -> Result<Self, Self> {
+        if wrap.source >= self.length || wrap.target >= self.length {
+            return Err(self)
+        }
+
+        let wrap = Wrap {source: wrap.source + self.offset, target: wrap.target + self.offset};
+        Ok(InstalledProgram {wrap, ..self})
+    }
+}
+
+pub struct UninitStateMachine<SM> {
+    _sm: PhantomData<SM>,
+}
+
+pub struct Stopped;
+
+pub struct Running;
+
+pub struct StateMachine<SM: ValidStateMachine, State> {
+    program: InstalledProgram<SM::PIO>,
+    _state: PhantomData<State>,
+}
+
+impl<SM: ValidStateMachine, State> StateMachine<SM, State> {
+    fn into_state<New>(self) -> StateMachine<SM, New> {
+        StateMachine {program: self.program, _state: PhantomData}
+    }
+
+    fn set_enabled(&self, enabled: bool) {
+        let mask = 1 << SM::index();
+
+        block_registers(SM::block()).ctrl().modify(|r, w| unsafe {
+            match enabled {
+                true => w.bits(r.bits() | mask),
+                false => w.bits(r.bits() & !mask),
+            }
+        });
+    }
+}
+
+impl<SM: ValidStateMachine> StateMachine<SM, Stopped> {
+    pub fn start(self) -> StateMachine<SM, Running> {
+        self.set_enabled(true);
+        self.into_state()
+    }
+
+    pub fn set_pindirs(&mut self, pindirs: impl IntoIterator<Item = (u8, PinDir)>) {
+        with_record(SM::block(), SM::index(), |record| {
+            for (pin, dir) in pindirs {
+                match dir {
+                    PinDir::Output => record.pindirs |= 1 << pin,
+                    PinDir::Input => record.pindirs &= !(1 << pin),
+                }
+            }
+        });
+    }
+
+    pub fn uninit(self, _rx: Rx<SM>, _tx: Tx<SM>) -> (UninitStateMachine<SM>, InstalledProgram<SM::PIO>) {
+        with_record(SM::block(), SM::index(), |record| record.config = None);
+
+        (UninitStateMachine {_sm: PhantomData}, self.program)
+    }
+}
+
+impl<SM: ValidStateMachine> StateMachine<SM, Running> {
+    pub fn stop(self) -> StateMachine<SM, Stopped> {
+        self.set_enabled(false);
+        self.into_state()
+    }
+}
+
+pub struct PIOBuilder<P> {
+    program: InstalledProgram<P>,
+    config: BuiltConfig,
+}
+
+impl<P: PIOExt> PIOBuilder<P> {
+    pub fn from_installed_program(program: InstalledProgram<P>) -> Self {
+        let config = BuiltConfig {
+            offset: program.offset,
+            wrap: program.wrap,
+            side_set: (program.side_set.optional(), program.side_set.bits(), program.side_set.pindirs()),
+            set_pins: (0, 0),
+            out_pins: (0, 0),
+            side_set_base: 0,
+            in_pin_base: 0,
+            jmp_pin: 0,
+            autopull: false,
+            pull_threshold: 0,
+            out_shift_direction: ShiftDirection::Right,
+            autopush: false,
+            push_threshold: 0,
+            in_shift_direction: ShiftDirection::Right,
+            buffers: Buffers::RxTx,
+            clock_divisor: (1, 0),
+        };
+
+        PIOBuilder {program, config}
+    }
+
+    pub fn set_pins(mut self, base: u8, count: u8) -> Self {
+        self.config.set_pins = (base, count);
+        self
+    }
+
+    pub fn out_pins(mut self, base: u8, count: u8) -> Self {
+        self.config.out_pins = (base, count);
+        self
+    }
+
+    pub fn side_set_pin_base(mut self, base: u8) -> Self {
+        self.config.side_set_base = base;
+        self
+    }
+
+    pub fn in_pin_base(mut self, base: u8) -> Self {
+        self.config.in_pin_base = base;
+        self
+    }
+
+    pub fn jmp_pin(mut self, pin: u8) -> Self {
+        self.config.jmp_pin = pin;
+        self
+    }
+
+    pub fn autopull(mut self, autopull: bool) -> Self {
+        self.config.autopull = autopull;
+        self
+    }
+
+    pub fn pull_threshold(mut self, threshold: u8) -> Self {
+        self.config.pull_threshold = threshold;
+        self
+    }
+
+    pub fn out_shift_direction(mut self, direction: ShiftDirection) -> Self {
+        self.config.out_shift_direction = direction;
+        self
+    }
+
+    pub fn autopush(mut self, autopush: bool) -> Self {
+        self.config.autopush = autopush;
+        self
+    }
+
+    pub fn push_threshold(mut self, threshold: u8) -> Self {
+        self.config.push_threshold = threshold;
+        self
+    }
+
+    pub fn in_shift_direction(mut self, direction: ShiftDirection) -> Self {
+        self.config.in_shift_direction = direction;
+        self
+    }
+
+    pub fn buffers(mut self, buffers: Buffers) -> Self {
+        self.config.buffers = buffers;
+        self
+    }
+
+    pub fn clock_divisor_fixed_point(mut self, int: u16, frac: u8) -> Self {
+        self.config.clock_divisor = (int, frac);
+        self
+    }
+
+    pub fn build<SM: StateMachineIndex>(
+        self,
+        _sm: UninitStateMachine<(P, SM)>,
+    ) -> Built<(P, SM)> {
+        with_record(P::id(), SM::id(), |record| record.config = Some(self.config));
+
+        let sm = StateMachine {program: self.program, _state: PhantomData};
+        (sm, Rx {_sm: PhantomData}, Tx {_sm: PhantomData})
+    }
+}
+
+pub struct Tx<SM> {
+    _sm: PhantomData<SM>,
+}
+
+impl<SM: ValidStateMachine> Tx<SM> {
+    pub fn fifo_address(&self) -> *const u32 {
+        core::ptr::null()
+    }
+
+    pub fn dreq_value(&self) -> u8 {
+        (SM::block() * 8 + SM::index()) as u8
+    }
+
+    pub fn write(&mut self, value: u32) -> bool {
+        with_record(SM::block(), SM::index(), |record| {
+            if record.tx_room == Some(0) {
+                return false
+            }
+
+            record.tx_room = record.tx_room.map(|room| room - 1);
+            record.fill(&[value]);
+            true
+        })
+    }
+
+    pub fn write_u8_replicated(&mut self, value: u8) -> bool {
+        self.write(u32::from_ne_bytes([value; 4]))
+    }
+
+    pub fn write_u16_replicated(&mut self, value: u16) -> bool {
+        self.write((value as u32) << 16 | value as u32)
+    }
+
+    pub fn has_stalled(&self) -> bool {
+        with_record(SM::block(), SM::index(), |record| {
+            record.pull();
+            record.tx_stalled
+        })
+    }
+
+    pub fn clear_stalled_flag(&self) {
+        with_record(SM::block(), SM::index(), |record| record.tx_stalled = false);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        with_record(SM::block(), SM::index(), |record| {
+            let empty = record.tx_level == 0;
+            record.pull();
+            empty
+        })
+    }
+
+    pub fn is_full(&self) -> bool {
+        with_record(SM::block(), SM::index(), |record| record.tx_room == Some(0))
+    }
+
+    pub fn enable_tx_not_full_interrupt(&self, _id: PioIRQ) {}
+
+    pub fn disable_tx_not_full_interrupt(&self, _id: PioIRQ) {}
+
+    pub fn force_tx_not_full_interrupt(&self, _id: PioIRQ) {}
+}
+
+pub struct Rx<SM> {
+    _sm: PhantomData<SM>,
+}
+
+impl<SM: ValidStateMachine> Rx<SM> {
+    pub fn fifo_address(&self) -> *const u32 {
+        core::ptr::null()
+    }
+
+    pub fn dreq_value(&self) -> u8 {
+        (SM::block() * 8 + SM::index() + 4) as u8
+    }
+
+    pub fn read(&mut self) -> Option<u32> {
+        with_record(SM::block(), SM::index(), |record| record.received.pop_front())
+    }
+
+    pub fn enable_autopush(&mut self, _enable: bool) {}
+
+    pub fn is_empty(&self) -> bool {
+        with_record(SM::block(), SM::index(), |record| record.received.is_empty())
+    }
+
+    pub fn is_full(&self) -> bool {
+        false
+    }
+
+    pub fn enable_rx_not_empty_interrupt(&self, _id: PioIRQ) {}
+
+    pub fn disable_rx_not_empty_interrupt(&self, _id: PioIRQ) {}
+
+    pub fn force_rx_not_empty_interrupt(&self, _id: PioIRQ, _state: bool) {}
+}
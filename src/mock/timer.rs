@@ -0,0 +1,44 @@
+//! A microsecond counter that only moves when something looks at it
+//!
+//! Every read moves time on by a microsecond, so loops waiting on the timer
+//! always finish. `Delay` moves it on by however long was asked for.
+
+use core::cell::Cell;
+
+std::thread_local! {
+    static NOW_US: Cell<u64> = const { Cell::new(0) };
+}
+
+/// Stands in for `rp2040_hal::Timer`
+#[derive(Clone, Copy)]
+pub struct Timer {
+    _private: (),
+}
+
+impl Timer {
+    pub fn new() -> Timer {
+        Timer {_private: ()}
+    }
+
+    /// The low 32 bits of the counter
+    pub fn get_counter_low(&self) -> u32 {
+        advance(1);
+        now_us() as u32
+    }
+}
+
+impl Default for Timer {
+    fn default() -> Self {
+        Timer::new()
+    }
+}
+
+/// Microseconds since the thread started
+pub fn now_us() -> u64 {
+    NOW_US.with(|now| now.get())
+}
+
+/// Moves time on
+pub fn advance(us: u64) {
+    NOW_US.with(|now| now.set(now.get() + us));
+}
@@ -0,0 +1,187 @@
+//! A single data line driven by a state machine
+//!
+//! This is the part `Strip` and `OutputManager` have in common: taking a pin,
+//! finding a block with room for the program, feeding the FIFO, and waiting
+//! for a frame to latch. They differ in when the state machines are started,
+//! so that's left to them.
+
+use crate::hal::pac::{PIO0, PIO1};
+use crate::hal::Timer;
+
+use crate::chipset::ProgramTiming;
+use crate::dma::DmaChannel;
+use crate::hardware::{self, Hardware};
+use crate::pio::{self, PioPin, ProgramId, StateMachineHandle};
+use crate::rx::Rx;
+use crate::state_machine;
+use crate::tx::Tx;
+use crate::ws2812b;
+
+#[derive(Debug)]
+pub enum Error {
+    /// The pin has already been taken, or doesn't exist
+    PinUnavailable,
+    /// Failed to install the program. If neither block could take the output,
+    /// this is why the last one tried couldn't.
+    Pio(pio::Error),
+    /// Failed to start the state machine
+    StateMachine(state_machine::Error),
+    /// Failed to give something back to the hardware
+    Hardware(hardware::Error),
+}
+
+impl From<pio::Error> for Error {
+    fn from(value: pio::Error) -> Self {Error::Pio(value)}
+}
+
+impl From<state_machine::Error> for Error {
+    fn from(value: state_machine::Error) -> Self {Error::StateMachine(value)}
+}
+
+impl From<hardware::Error> for Error {
+    fn from(value: hardware::Error) -> Self {Error::Hardware(value)}
+}
+
+/// A pin with a programmed state machine, and a DMA channel if there was one
+/// to spare
+pub struct Output {
+    pin: PioPin,
+    handle: StateMachineHandle,
+    program: ProgramId,
+    rx: Rx,
+    pub tx: Tx,
+    pub dma: Option<DmaChannel>,
+}
+
+impl Output {
+    /// Takes a pin and programs a state machine to drive it, without starting
+    /// it
+    ///
+    /// The state machine comes from PIO0 if it has one and room for the
+    /// program, otherwise from PIO1. Everything is given back if neither can
+    /// take it.
+    ///
+    /// * `pin` - GPIO number of the data line
+    /// * `bits_per_pixel` - 24 for RGB or 32 for RGBW
+    pub fn claim(
+        hardware: &mut Hardware,
+        pin: u8,
+        timing: &ProgramTiming,
+        bits_per_pixel: u8,
+    ) -> Result<Output, Error> {
+        let dyn_pin = hardware.take_pin(pin).ok_or(Error::PinUnavailable)?;
+
+        let pio0 = match hardware.get_pio0_mut() {
+            Some(pio) => ws2812b::install(pio, timing, pin, bits_per_pixel),
+            None => Err(pio::Error::MissingPIO),
+        };
+
+        let (pin, handle, program, (rx, tx)) = match pio0 {
+            Ok((handle, program, rxtx)) => (pio::connect_pin::<PIO0>(dyn_pin), handle, program, rxtx.split()),
+            Err(_) => {
+                let pio1 = match hardware.get_pio1_mut() {
+                    Some(pio) => ws2812b::install(pio, timing, pin, bits_per_pixel),
+                    None => Err(pio::Error::MissingPIO),
+                };
+
+                let (handle, program, rxtx) = match pio1 {
+                    Ok(installed) => installed,
+                    Err(error) => {
+                        hardware.return_pin(dyn_pin)?;
+                        return Err(error.into())
+                    },
+                };
+
+                (pio::connect_pin::<PIO1>(dyn_pin), handle, program, rxtx.split())
+            },
+        };
+
+        let dma = hardware.take_dma_channel();
+
+        Ok(Output {pin, handle, program, rx, tx, dma})
+    }
+
+    /// Stops the state machine and gives back the pin, state machine, program,
+    /// and DMA channel
+    pub fn free(self, hardware: &mut Hardware) -> Result<(), Error> {
+        let block = self.block();
+
+        if let Some(mut dma) = self.dma {
+            dma.abort();
+            dma.set_callback(None);
+            hardware.return_dma_channel(dma)?;
+        }
+
+        let (mut pio0, mut pio1) = hardware.get_pios_mut();
+        pio::release(pio0.as_deref_mut(), pio1.as_deref_mut(), self.handle, self.rx, self.tx)?;
+
+        match (block, pio0, pio1) {
+            (0, Some(pio0), _) => pio0.unload_program(self.program)?,
+            (1, _, Some(pio1)) => pio1.unload_program(self.program)?,
+            _ => return Err(Error::Pio(pio::Error::MissingPIO)),
+        }
+
+        hardware.return_pin(pio::disconnect_pin(self.pin))?;
+
+        Ok(())
+    }
+
+    /// Which PIO block the state machine is on
+    pub fn block(&self) -> u8 {
+        self.tx.block()
+    }
+
+    /// Bit mask of the state machine within its block
+    pub fn mask(&self) -> u8 {
+        self.handle.mask()
+    }
+
+    /// Starts the state machine on its own
+    pub fn start(&self, hardware: &mut Hardware) -> Result<(), Error> {
+        let started = match (self.block(), hardware.get_pios_mut()) {
+            (0, (Some(pio0), _)) => pio0.start_synchronized(self.mask()),
+            (1, (_, Some(pio1))) => pio1.start_synchronized(self.mask()),
+            _ => return Err(Error::Pio(pio::Error::MissingPIO)),
+        };
+
+        Ok(started?)
+    }
+
+    /// Returns whether or not a frame is still being clocked out
+    pub fn is_busy(&self) -> bool {
+        let transferring = self.dma.as_ref().is_some_and(|dma| dma.is_busy());
+
+        transferring || !self.tx.is_empty()
+    }
+
+    /// Waits for the DMA to finish reading the frame
+    pub fn wait_for_transfer(&self) {
+        if let Some(dma) = &self.dma {
+            dma.wait();
+        }
+    }
+
+    /// Waits for the state machine to run out of bits
+    ///
+    /// The line is low from here on, but the strip only latches once it has
+    /// stayed low for the chipset's reset time. See `latch`.
+    pub fn wait_until_sent(&self) {
+        self.wait_for_transfer();
+
+        while !self.tx.is_empty() {}
+        self.tx.clear_stalled_flag();
+        while !self.tx.has_stalled() {}
+    }
+}
+
+/// Holds every line low long enough for the strips on it to latch
+///
+/// This spins on the system timer rather than borrowing the `Delay`, so it
+/// still waits if the application has taken that out of the hardware.
+///
+/// * `reset_us` - The longest reset time of any strip that was just sent
+pub fn latch(timer: &Timer, reset_us: u32) {
+    let start = timer.get_counter_low();
+
+    while timer.get_counter_low().wrapping_sub(start) < reset_us {}
+}
@@ -0,0 +1,453 @@
+//! Drives up to 8 independent strips at once, one per state machine
+//!
+//! Every channel has its own pin, chipset, and length. It takes its pin from
+//! the hardware and claims its own state machine, from PIO0 first and then
+//! PIO1. Channels with the same program delays share instruction memory.
+//!
+//! `refresh` starts every channel before waiting on any of them, so all the
+//! strips are clocked out at the same time rather than one after another.
+
+use alloc::vec::Vec;
+
+use crate::chipset::Chipset;
+use crate::color::{ColorOrder, Rgb8, Rgbw8};
+use crate::correction::Correction;
+use crate::dither;
+use crate::hal::Timer;
+use crate::hardware::{self, Hardware};
+use crate::output::{self, Output};
+use crate::pio;
+use crate::state_machine;
+
+/// Most channels that can be driven at once, one per state machine
+pub const MAX_CHANNELS: usize = 8;
+
+#[derive(Debug)]
+pub enum Error {
+    /// Hardware hasn't been initialized yet
+    NoHardware,
+    /// More channels were asked for than there are state machines
+    TooManyChannels,
+    /// A channel's timing can't be hit with the current system clock
+    UnsupportedTiming,
+    /// There is no channel with that index
+    NoSuchChannel,
+    /// A channel's pin has already been taken, or doesn't exist
+    PinUnavailable,
+    /// Failed to install the program. If neither block could take a channel,
+    /// this is why the last one tried couldn't.
+    Pio(pio::Error),
+    /// Failed to start, stop, or uninstall a state machine
+    StateMachine(state_machine::Error),
+    /// Failed to give something back to the hardware
+    Hardware(hardware::Error),
+}
+
+impl From<pio::Error> for Error {
+    fn from(value: pio::Error) -> Self {Error::Pio(value)}
+}
+
+impl From<state_machine::Error> for Error {
+    fn from(value: state_machine::Error) -> Self {Error::StateMachine(value)}
+}
+
+impl From<hardware::Error> for Error {
+    fn from(value: hardware::Error) -> Self {Error::Hardware(value)}
+}
+
+impl From<output::Error> for Error {
+    fn from(value: output::Error) -> Self {
+        match value {
+            output::Error::PinUnavailable => Error::PinUnavailable,
+            output::Error::Pio(error) => Error::Pio(error),
+            output::Error::StateMachine(error) => Error::StateMachine(error),
+            output::Error::Hardware(error) => Error::Hardware(error),
+        }
+    }
+}
+
+/// How a single channel is wired up
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ChannelConfig {
+    /// GPIO number of the data line
+    pub pin: u8,
+    pub chipset: Chipset,
+    pub color_order: ColorOrder,
+    /// Number of pixels on the strip
+    pub length: usize,
+}
+
+impl ChannelConfig {
+    pub const fn new(pin: u8, chipset: Chipset, color_order: ColorOrder, length: usize) -> ChannelConfig {
+        ChannelConfig {pin, chipset, color_order, length}
+    }
+}
+
+/// A single output of the manager
+struct Channel {
+    output: Output,
+    color_order: ColorOrder,
+    length: usize,
+    reset_us: u32,
+    correction: Correction,
+    /// Packed pixels, always exactly `length` words
+    words: Vec<u32>,
+}
+
+/// Up to 8 strips, refreshed together
+pub struct OutputManager {
+    /// In the same order as the configs they were made from
+    channels: Vec<Channel>,
+    /// Whether a refresh has been sent that hasn't been waited out yet
+    latch_pending: bool,
+    timer: Timer,
+}
+
+impl OutputManager {
+    /// Sets up a channel for each config
+    ///
+    /// Each channel claims a state machine from PIO0 while it has any left,
+    /// then from PIO1. Every channel takes a DMA channel while there are some
+    /// left, otherwise it's fed by the CPU. Once they're all set up, every
+    /// state machine is started together so the strips are clocked in phase.
+    pub fn new(configs: &[ChannelConfig]) -> Result<OutputManager, Error> {
+        if configs.len() > MAX_CHANNELS {
+            return Err(Error::TooManyChannels)
+        }
+
+        let hardware = Hardware::get().ok_or(Error::NoHardware)?;
+        let system_clock_hz = hardware.system_clock_hz();
+
+        let mut manager = OutputManager {
+            channels: Vec::with_capacity(configs.len()),
+            latch_pending: false,
+            timer: hardware.timer(),
+        };
+
+        for config in configs {
+            match manager.add(hardware, config, system_clock_hz) {
+                Ok(()) => {},
+                Err(error) => {
+                    // Give back whatever was set up before the failure. Any
+                    // error doing so would only hide why it failed.
+                    let _ = manager.free();
+                    return Err(error)
+                },
+            }
+        }
+
+        let mut mask0 = 0;
+        let mut mask1 = 0;
+
+        for channel in &manager.channels {
+            match channel.output.block() {
+                0 => mask0 |= channel.output.mask(),
+                _ => mask1 |= channel.output.mask(),
+            }
+        }
+
+        let started = match hardware.get_pios_mut() {
+            (Some(pio0), Some(pio1)) => pio::start_synchronized(pio0, mask0, pio1, mask1),
+            (Some(pio0), None) => pio0.start_synchronized(mask0),
+            (None, Some(pio1)) => pio1.start_synchronized(mask1),
+            (None, None) => Ok(()),
+        };
+
+        if let Err(error) = started {
+            let _ = manager.free();
+            return Err(error.into())
+        }
+
+        Ok(manager)
+    }
+
+    /// Stops every channel and gives back the pins, state machines, and DMA
+    /// channels
+    ///
+    /// Dropping the manager does the same, but without a way to see if it
+    /// worked.
+    pub fn free(mut self) -> Result<(), Error> {
+        self.release()
+    }
+
+    /// Number of channels
+    pub fn len(&self) -> usize {
+        self.channels.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.channels.is_empty()
+    }
+
+    /// Number of pixels on a channel
+    pub fn length(&self, channel: usize) -> Option<usize> {
+        self.channels.get(channel).map(|channel| channel.length)
+    }
+
+    /// Sets the global brightness of a channel
+    pub fn set_brightness(&mut self, channel: usize, brightness: u8) -> Result<(), Error> {
+        let channel = self.channels.get_mut(channel).ok_or(Error::NoSuchChannel)?;
+        channel.correction.brightness = brightness;
+
+        Ok(())
+    }
+
+    /// Packs a frame for a channel, ready for the next `refresh`
+    ///
+    /// Extra pixels past the channel's length are dropped, and missing ones
+    /// are sent as black. This waits for the channel's last frame to finish
+    /// sending first, since that's the buffer being packed into.
+    pub fn write(&mut self, channel: usize, pixels: &[Rgb8]) -> Result<(), Error> {
+        let channel = self.channels.get_mut(channel).ok_or(Error::NoSuchChannel)?;
+        channel.output.wait_for_transfer();
+
+        let Channel {color_order, correction, words, ..} = channel;
+        let has_white = color_order.channels() == 4;
+
+        let pixels = pixels.iter().copied().chain(core::iter::repeat(Rgb8::default()));
+
+        for (word, pixel) in words.iter_mut().zip(pixels) {
+            let pixel = match has_white {
+                true => Rgbw8::extract_white(pixel),
+                false => pixel.into(),
+            };
+
+            *word = color_order.pack(dither::round(correction.apply_rgbw(pixel)));
+        }
+
+        Ok(())
+    }
+
+    /// Sends the last written frame out on every channel at once
+    ///
+    /// Waits for the previous refresh to latch first. Channels with DMA are
+    /// all started before any CPU fed ones, which are then filled in turn so
+    /// none of them fall behind.
+    pub fn refresh(&mut self) {
+        self.wait_for_latch();
+
+        for channel in &mut self.channels {
+            let Output {tx, dma, ..} = &mut channel.output;
+
+            if let Some(dma) = dma {
+                unsafe {
+                    // The words are only written again after the transfer
+                    // finishes, and dropping the manager stops it first
+                    dma.start(&channel.words, tx);
+                }
+            }
+        }
+
+        let mut positions = [0; MAX_CHANNELS];
+        let mut remaining = true;
+
+        while remaining {
+            remaining = false;
+
+            for (channel, position) in self.channels.iter_mut().zip(positions.iter_mut()) {
+                if channel.output.dma.is_some() {
+                    continue
+                }
+
+                if let Some(&word) = channel.words.get(*position) {
+                    if channel.output.tx.write(word) {
+                        *position += 1;
+                    }
+
+                    remaining = true;
+                }
+            }
+        }
+
+        self.latch_pending = true;
+    }
+
+    /// Returns whether or not any channel is still clocking out a frame
+    pub fn is_busy(&self) -> bool {
+        self.channels.iter().any(|channel| channel.output.is_busy())
+    }
+
+    /// Takes the pin for a channel, then claims a state machine and programs
+    /// it, without starting it
+    fn add(&mut self, hardware: &mut Hardware, config: &ChannelConfig, system_clock_hz: u32) -> Result<(), Error> {
+        let timing = config.chipset
+            .timing()
+            .program_timing(system_clock_hz)
+            .ok_or(Error::UnsupportedTiming)?;
+
+        let output = Output::claim(hardware, config.pin, &timing, config.color_order.bits_per_pixel())?;
+
+        self.channels.push(Channel {
+            output,
+            color_order: config.color_order,
+            length: config.length,
+            reset_us: config.chipset.timing().reset_us,
+            correction: Correction::default(),
+            words: alloc::vec![0; config.length],
+        });
+
+        Ok(())
+    }
+
+    /// Waits for every channel to clock out and latch its last frame
+    ///
+    /// Returns straight away if nothing has been sent since the last wait.
+    fn wait_for_latch(&mut self) {
+        if !self.latch_pending {
+            return
+        }
+
+        for channel in &self.channels {
+            channel.output.wait_until_sent();
+        }
+
+        // The strips were all sent together, so they can latch together
+        let reset_us = self.channels.iter().map(|channel| channel.reset_us).max().unwrap_or(0);
+        output::latch(&self.timer, reset_us);
+
+        self.latch_pending = false;
+    }
+
+    /// Stops the DMA and gives every channel back, if that hasn't happened yet
+    ///
+    /// Carries on past a channel that fails, returning the first error.
+    fn release(&mut self) -> Result<(), Error> {
+        let mut channels = core::mem::take(&mut self.channels);
+
+        // Stop reading every buffer first, even if nothing else can be given
+        // back
+        for channel in &mut channels {
+            if let Some(dma) = &mut channel.output.dma {
+                dma.abort();
+            }
+        }
+
+        let hardware = Hardware::get().ok_or(Error::NoHardware)?;
+        let mut result = Ok(());
+
+        for channel in channels {
+            result = result.and(channel.output.free(hardware).map_err(Error::from));
+        }
+
+        result
+    }
+}
+
+impl Drop for OutputManager {
+    /// Makes sure the DMA is done with every channel's buffer before it's
+    /// freed
+    fn drop(&mut self) {
+        let _ = self.release();
+    }
+}
+
+#[cfg(all(test, feature = "host"))]
+mod tests {
+    use super::*;
+
+    use crate::dma;
+    use crate::mock;
+
+    const RED: Rgb8 = Rgb8::new(255, 0, 0);
+
+    fn config(pin: u8, length: usize) -> ChannelConfig {
+        ChannelConfig::new(pin, Chipset::Ws2812b, ColorOrder::Grb, length)
+    }
+
+    #[test]
+    fn channels_start_together_and_send_their_own_frames() {
+        Hardware::init(12_000_000);
+
+        let mut manager = OutputManager::new(&[config(2, 2), config(3, 1), config(4, 3)]).unwrap();
+        assert_eq!(mock::pio::enabled_mask(0), 0b0111);
+
+        manager.write(0, &[RED, RED]).unwrap();
+        // Extra pixels are dropped, missing ones are black
+        manager.write(1, &[RED, RED]).unwrap();
+        manager.write(2, &[RED]).unwrap();
+        manager.refresh();
+
+        assert_eq!(mock::pio::take_sent(0, 0), [0x00ff0000, 0x00ff0000]);
+        assert_eq!(mock::pio::take_sent(0, 1), [0x00ff0000]);
+        assert_eq!(mock::pio::take_sent(0, 2), [0x00ff0000, 0, 0]);
+
+        manager.free().unwrap();
+        assert_eq!(mock::pio::enabled_mask(0), 0);
+    }
+
+    #[test]
+    fn dropping_gives_everything_back() {
+        Hardware::init(12_000_000);
+
+        let mut manager = OutputManager::new(&[config(2, 2), config(3, 1)]).unwrap();
+        manager.write(0, &[RED]).unwrap();
+        manager.refresh();
+        drop(manager);
+
+        let hardware = Hardware::get().unwrap();
+        assert!(hardware.take_pin(2).is_some());
+        assert!(hardware.take_pin(3).is_some());
+        assert_eq!(core::iter::from_fn(|| hardware.take_dma_channel()).count(), dma::NUM_CHANNELS);
+
+        let pio0 = hardware.get_pio0_mut().unwrap();
+        assert_eq!(mock::pio::enabled_mask(0), 0);
+        assert_eq!(pio0.claimed_mask(), 0);
+        assert_eq!(pio0.free_instructions(), 32);
+    }
+
+    #[test]
+    fn overflows_onto_pio1_and_feeds_without_dma() {
+        Hardware::init(12_000_000);
+
+        // Leave no DMA channels, so every word goes through the CPU
+        let hardware = Hardware::get().unwrap();
+        while hardware.take_dma_channel().is_some() {}
+
+        let configs: Vec<ChannelConfig> = (0..6).map(|pin| config(pin, 1)).collect();
+        let mut manager = OutputManager::new(&configs).unwrap();
+
+        assert_eq!(mock::pio::enabled_mask(0), 0b1111);
+        assert_eq!(mock::pio::enabled_mask(1), 0b0011);
+
+        for channel in 0..manager.len() {
+            manager.write(channel, &[RED]).unwrap();
+        }
+
+        manager.refresh();
+        assert_eq!(mock::pio::take_sent(1, 1), [0x00ff0000]);
+    }
+
+    #[test]
+    fn only_waits_to_latch_after_sending() {
+        Hardware::init(12_000_000);
+
+        let mut manager = OutputManager::new(&[config(2, 1)]).unwrap();
+
+        let start = mock::timer::now_us();
+        manager.refresh();
+        assert_eq!(mock::timer::now_us(), start);
+
+        manager.refresh();
+        assert!(mock::timer::now_us() - start >= Chipset::Ws2812b.timing().reset_us as u64);
+    }
+
+    #[test]
+    fn failing_channels_give_back_the_earlier_ones() {
+        Hardware::init(12_000_000);
+
+        let hardware = Hardware::get().unwrap();
+        let taken = hardware.take_pin(3).unwrap();
+
+        let result = OutputManager::new(&[config(2, 1), config(3, 1)]);
+        assert!(matches!(result, Err(Error::PinUnavailable)));
+
+        assert!(hardware.take_pin(2).is_some());
+        assert_eq!(mock::pio::enabled_mask(0), 0);
+        assert!(mock::pio::record(0, 0).config.is_none());
+
+        hardware.return_pin(taken).unwrap();
+
+        let configs = [config(0, 1); MAX_CHANNELS + 1];
+        assert!(matches!(OutputManager::new(&configs), Err(Error::TooManyChannels)));
+    }
+}
@@ -0,0 +1,136 @@
+//! Drives up to 8 strips from a single state machine
+//!
+//! The strips sit on consecutive pins and get clocked out together, one bit of
+//! every strip per step. That means the pixel data has to be transposed first,
+//! so that each byte in the FIFO holds the same bit of the same pixel for all
+//! 8 strips.
+
+use alloc::vec::Vec;
+
+use pio::Program;
+use crate::hal::pio::{Buffers, ShiftDirection};
+
+use crate::clock_divisor::ClockDivisor;
+use crate::state_machine::StateMachineConfig;
+
+/// Most strips one state machine can drive
+pub const MAX_STRIPS: usize = 8;
+
+/// Number of PIO cycles it takes to send a single bit
+pub const CYCLES_PER_BIT: u32 = 10;
+
+/// Assembles the parallel program from ws2812b_parallel.pio
+pub fn program() -> Program<32> {
+    let program = pio_proc::pio_file!("src/ws2812b_parallel.pio", select_program("ws2812b_parallel"));
+
+    program.program
+}
+
+/// State machine config for the parallel program
+///
+/// The pins are used as out pins and set to outputs, and the output shift
+/// register autopulls a whole word at a time from a TX only FIFO.
+///
+/// * `pins` - Base and count of the output pins, at most 8
+pub fn config(pins: (u8, u8), clock_divisor: ClockDivisor) -> StateMachineConfig {
+    StateMachineConfig {
+        out_pins: Some(pins),
+        output_pins: Some(pins),
+        autopull: true,
+        pull_threshold: 32,
+        out_shift_direction: ShiftDirection::Left,
+        buffers: Buffers::OnlyTx,
+        ..StateMachineConfig::new(clock_divisor)
+    }
+}
+
+/// Turns per strip pixel words into bit planes for the FIFO
+///
+/// * `strips` - Packed pixels for each strip, as made by `ColorOrder::pack`.
+///   Strip `n` ends up on pin `base + n`, and shorter strips are padded with
+///   black.
+/// * `bits_per_pixel` - 24 for RGB strips, or 32 for RGBW
+/// * `output` - Cleared, then filled with words ready to write to the FIFO
+pub fn transpose(strips: &[&[u32]], bits_per_pixel: u8, output: &mut Vec<u32>) {
+    let strips = &strips[..strips.len().min(MAX_STRIPS)];
+    let length = strips.iter().map(|strip| strip.len()).max().unwrap_or(0);
+
+    output.clear();
+
+    let mut word = 0;
+    let mut bytes_in_word = 0;
+
+    for pixel in 0..length {
+        // Pixels are MSB first, starting from the top of the word
+        for bit in (32 - bits_per_pixel as u32..32).rev() {
+            let mut plane = 0;
+
+            for (index, strip) in strips.iter().enumerate() {
+                let value = strip.get(pixel).copied().unwrap_or(0);
+                plane |= ((value >> bit) & 1) << index;
+            }
+
+            // The first plane out goes in the most significant byte
+            word = word << 8 | plane;
+            bytes_in_word += 1;
+
+            if bytes_in_word == 4 {
+                output.push(word);
+                word = 0;
+                bytes_in_word = 0;
+            }
+        }
+    }
+
+    // Pad out the last word with zeros
+    if bytes_in_word > 0 {
+        output.push(word << (8 * (4 - bytes_in_word)));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn transposed(strips: &[&[u32]], bits_per_pixel: u8) -> Vec<u32> {
+        let mut output = Vec::new();
+        transpose(strips, bits_per_pixel, &mut output);
+        output
+    }
+
+    #[test]
+    fn each_strip_lands_on_its_own_bit() {
+        let on: &[u32] = &[0xffffff00];
+        let off: &[u32] = &[0];
+
+        // 24 planes of every other strip, 4 to a word
+        let output = transposed(&[on, off, on, off, on, off, on, off], 24);
+        assert_eq!(output, [0x55555555; 6]);
+    }
+
+    #[test]
+    fn planes_go_out_msb_first() {
+        // The first bit of strip 0 and the last bit of strip 7
+        let output = transposed(&[&[0x80000000], &[], &[], &[], &[], &[], &[], &[0x00000100]], 24);
+
+        assert_eq!(output, [0x01000000, 0, 0, 0, 0, 0x00000080]);
+    }
+
+    #[test]
+    fn rgbw_sends_all_32_bits() {
+        // Only the white channel's last bit, on strip 1
+        let output = transposed(&[&[0], &[0x00000001]], 32);
+
+        assert_eq!(output, [0, 0, 0, 0, 0, 0, 0, 0x00000002]);
+    }
+
+    #[test]
+    fn short_strips_are_padded_with_black() {
+        let output = transposed(&[&[0xff000000, 0xff000000], &[0xff000000]], 24);
+
+        assert_eq!(output, [
+            0x03030303, 0x03030303, 0, 0, 0, 0,
+            0x01010101, 0x01010101, 0, 0, 0, 0,
+        ]);
+    }
+}
@@ -1,8 +1,16 @@
-use pio::{ArrayVec, Program};
-use rp2040_hal::pac::RESETS;
-use rp2040_hal::pio::{PIOExt, Rx, Tx, PIO, SM0, SM1, SM2, SM3};
+use alloc::vec::Vec;
 
-use crate::state_machine::{self, StateMachine};
+use pio::{ArrayVec, Program, SideSet, Wrap};
+use crate::hal::gpio::{DynFunction, DynPinId, OutputDriveStrength, OutputSlewRate, Pin, PullDown};
+use crate::hal::pac::{self, PIO0, PIO1, RESETS};
+use crate::hal::pio::{InstalledProgram, PIOExt, Rx, Tx, PIO, SM0, SM1, SM2, SM3};
+
+use crate::clock_divisor::ClockDivisor;
+use crate::hardware::DynPin;
+use crate::parallel;
+use crate::rx;
+use crate::state_machine::{self, StateMachine, StateMachineConfig};
+use crate::tx;
 
 #[derive(Debug)]
 pub enum Error {
@@ -12,6 +20,52 @@ pub enum Error {
     TooManyStateMachinesRequested,
     /// Bad State machine programming input
     BadStateMachineProgramming,
+    /// There isn't enough instruction memory left for the program
+    NoSpace,
+    /// There is no loaded program with that id, or it has been unloaded
+    NoSuchProgram,
+    /// A state machine is still running the program
+    ProgramInUse,
+    /// The requested state machine has already been claimed
+    StateMachineInUse,
+    /// Every state machine on the block has already been claimed
+    NoFreeStateMachine,
+    /// The rx and tx don't belong to the state machine being released
+    WrongStateMachine,
+    /// The state machine is still programmed, so it has to be released along
+    /// with its rx and tx
+    StillProgrammed,
+    /// The PIO block has been taken out of the hardware
+    MissingPIO,
+}
+
+/// Which state machine to claim
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Select {
+    /// Whichever one is free, starting from state machine 0
+    Any,
+    /// A specific one, from 0 to 3
+    Index(u8),
+}
+
+/// Proof that a state machine has been claimed
+///
+/// Hand it back with `Pio::release` once the state machine isn't needed.
+#[derive(Debug, PartialEq, Eq)]
+pub struct StateMachineHandle {
+    index: u8,
+}
+
+impl StateMachineHandle {
+    /// The index of the claimed state machine, from 0 to 3
+    pub fn index(&self) -> u8 {
+        self.index
+    }
+
+    /// Bit mask with just this state machine set, for `Pio::start_synchronized`
+    pub fn mask(&self) -> u8 {
+        1 << self.index
+    }
 }
 
 pub enum RxTx<P: PIOExt> {
@@ -21,13 +75,81 @@ pub enum RxTx<P: PIOExt> {
     SM3(Rx<(P, SM3)>, Tx<(P, SM3)>),
 }
 
+impl<P: PIOExt> RxTx<P> {
+    /// The index of the state machine these belong to
+    pub fn index(&self) -> u8 {
+        match self {
+            RxTx::SM0(_, _) => 0,
+            RxTx::SM1(_, _) => 1,
+            RxTx::SM2(_, _) => 2,
+            RxTx::SM3(_, _) => 3,
+        }
+    }
+}
+
+impl RxTx<PIO0> {
+    /// Splits into the rx and tx enums
+    pub fn split(self) -> (rx::Rx, tx::Tx) {
+        match self {
+            RxTx::SM0(rx, tx) => (rx.into(), tx.into()),
+            RxTx::SM1(rx, tx) => (rx.into(), tx.into()),
+            RxTx::SM2(rx, tx) => (rx.into(), tx.into()),
+            RxTx::SM3(rx, tx) => (rx.into(), tx.into()),
+        }
+    }
+}
+
+impl RxTx<PIO1> {
+    /// Splits into the rx and tx enums
+    pub fn split(self) -> (rx::Rx, tx::Tx) {
+        match self {
+            RxTx::SM0(rx, tx) => (rx.into(), tx.into()),
+            RxTx::SM1(rx, tx) => (rx.into(), tx.into()),
+            RxTx::SM2(rx, tx) => (rx.into(), tx.into()),
+            RxTx::SM3(rx, tx) => (rx.into(), tx.into()),
+        }
+    }
+}
+
+/// Identifies a program loaded into a PIO block's instruction memory
+///
+/// Every id from `load_program` keeps the program loaded until it's given to
+/// `unload_program`. After that the id is stale, and using it again fails even
+/// if another program has since been loaded to the same place.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ProgramId {
+    offset: u8,
+    /// Counts up with every program loaded, to tell apart programs that were
+    /// loaded to the same place at different times
+    generation: u16,
+}
+
+/// A program sitting in instruction memory
+struct LoadedProgram<P: PIOExt> {
+    installed: InstalledProgram<P>,
+    /// The code as it was before being relocated, to spot the same program
+    /// being loaded twice
+    code: ArrayVec<u16, 32>,
+    wrap: Wrap,
+    side_set: SideSet,
+    generation: u16,
+    /// Number of ids handed out by `load_program` that haven't been unloaded
+    loads: u8,
+    /// Bit n is set while state machine n is running this program
+    users: u8,
+}
+
 pub struct Pio<P: PIOExt> {
     pio: PIO<P>,
     sm0: StateMachine<P, SM0>,
     sm1: StateMachine<P, SM1>,
     sm2: StateMachine<P, SM2>,
     sm3: StateMachine<P, SM3>,
-    in_use: bool,
+    programs: Vec<LoadedProgram<P>>,
+    /// Generation of the next program loaded, see `ProgramId`
+    generation: u16,
+    /// Bit n is set while state machine n is claimed
+    claimed: u8,
 }
 
 impl<P: PIOExt> Pio<P> {
@@ -39,117 +161,377 @@ impl<P: PIOExt> Pio<P> {
         let sm2 = StateMachine::new(sm2);
         let sm3 = StateMachine::new(sm3);
 
-        Pio {pio, sm0, sm1, sm2, sm3, in_use: false}
+        Pio {pio, sm0, sm1, sm2, sm3, programs: Vec::new(), generation: 0, claimed: 0}
+    }
+
+    /// Loads a program into instruction memory without starting anything
+    ///
+    /// Several different programs can be loaded at once, as long as they fit
+    /// in the 32 instructions of the block. Loading a program that is already
+    /// there reuses it instead of taking up more space, and returns the same
+    /// id. Every load needs its own `unload_program`.
+    pub fn load_program(&mut self, program: &Program<32>) -> Result<ProgramId, Error> {
+        if let Some(index) = self.find_program(program) {
+            let loaded = &mut self.programs[index];
+            loaded.loads += 1;
+
+            return Ok(ProgramId {offset: loaded.installed.offset(), generation: loaded.generation})
+        }
+
+        let installed = self.pio.install(program).map_err(|_| Error::NoSpace)?;
+        let id = ProgramId {offset: installed.offset(), generation: self.generation};
+        self.generation = self.generation.wrapping_add(1);
+
+        self.programs.push(LoadedProgram {
+            installed,
+            code: program.code.clone(),
+            wrap: program.wrap,
+            side_set: program.side_set,
+            generation: id.generation,
+            loads: 1,
+            users: 0,
+        });
+        Ok(id)
+    }
+
+    /// Gives back an id from `load_program`
+    ///
+    /// The program's instruction memory is freed once every load of it has
+    /// been given back. Fails if this is the last one and a state machine is
+    /// still running the program, or if the id is stale.
+    pub fn unload_program(&mut self, id: ProgramId) -> Result<(), Error> {
+        let index = self.program_index(id).ok_or(Error::NoSuchProgram)?;
+        let loaded = &mut self.programs[index];
+
+        if loaded.loads == 1 && loaded.users != 0 {
+            return Err(Error::ProgramInUse)
+        }
+
+        loaded.loads -= 1;
+
+        if loaded.loads == 0 {
+            let loaded = self.programs.swap_remove(index);
+            self.pio.uninstall(loaded.installed);
+        }
+
+        Ok(())
+    }
+
+    /// Number of instructions left for more programs
+    pub fn free_instructions(&self) -> u8 {
+        let used: usize = self.programs.iter().map(|loaded| loaded.code.len()).sum();
+
+        32 - used as u8
+    }
+
+    /// Claims a state machine so nothing else can use it
+    pub fn claim(&mut self, select: Select) -> Result<StateMachineHandle, Error> {
+        let index = match select {
+            Select::Any => (0..4)
+                .find(|&index| self.claimed & 1 << index == 0)
+                .ok_or(Error::NoFreeStateMachine)?,
+            Select::Index(index) if index >= 4 => return Err(Error::TooManyStateMachinesRequested),
+            Select::Index(index) if self.claimed & 1 << index != 0 => return Err(Error::StateMachineInUse),
+            Select::Index(index) => index,
+        };
+
+        self.claimed |= 1 << index;
+        Ok(StateMachineHandle {index})
+    }
+
+    /// Programs a claimed state machine to run a loaded program
+    ///
+    /// The state machine is left stopped. Start it with `start_synchronized`
+    /// and the handle's mask.
+    pub fn program(
+        &mut self,
+        handle: &StateMachineHandle,
+        id: ProgramId,
+        config: &StateMachineConfig,
+    ) -> Result<RxTx<P>, Error> {
+        let position = self.program_index(id).ok_or(Error::NoSuchProgram)?;
+        let installed = &self.programs[position].installed;
+        let index = handle.index;
+
+        let rxtx = match index {
+            0 => self.sm0.program(installed, config).map(|(rx, tx)| RxTx::SM0(rx, tx)),
+            1 => self.sm1.program(installed, config).map(|(rx, tx)| RxTx::SM1(rx, tx)),
+            2 => self.sm2.program(installed, config).map(|(rx, tx)| RxTx::SM2(rx, tx)),
+            3 => self.sm3.program(installed, config).map(|(rx, tx)| RxTx::SM3(rx, tx)),
+            _ => return Err(Error::TooManyStateMachinesRequested),
+        };
+
+        let Ok(rxtx) = rxtx else {
+            return Err(Error::BadStateMachineProgramming)
+        };
+
+        self.programs[position].users |= 1 << index;
+        Ok(rxtx)
+    }
+
+    /// Gives back a claimed state machine
+    ///
+    /// * `rxtx` - The rx and tx from `program`, if it was programmed. The state
+    ///   machine is stopped and uninstalled first. Its program stays loaded
+    ///   until `unload_program`.
+    ///
+    /// A programmed state machine can't be released without its rx and tx,
+    /// since it would otherwise be left running with nothing to stop it.
+    pub fn release(&mut self, handle: StateMachineHandle, rxtx: Option<RxTx<P>>) -> Result<(), Error> {
+        match rxtx {
+            Some(rxtx) => {
+                if rxtx.index() != handle.index {
+                    return Err(Error::WrongStateMachine)
+                }
+
+                self.uninstall_state_machine(rxtx).map_err(|_| Error::BadStateMachineProgramming)?;
+            },
+            None if self.initialized_mask() & handle.mask() != 0 => return Err(Error::StillProgrammed),
+            None => {},
+        }
+
+        self.claimed &= !handle.mask();
+        Ok(())
+    }
+
+    /// Bit mask of the state machines that have been claimed
+    pub fn claimed_mask(&self) -> u8 {
+        self.claimed
+    }
+
+    /// Number of state machines that haven't been claimed yet
+    pub fn free_state_machines(&self) -> u8 {
+        4 - self.claimed.count_ones() as u8
     }
 
     /// Installs a program
     ///
+    /// The program is loaded into instruction memory if it isn't there already,
+    /// then run on the first `NUM` state machines that haven't been claimed.
+    /// They stay claimed, and the program stays loaded, until
+    /// `unininstall_program`.
+    ///
+    /// * `configs` - How to set up each state machine
+    ///
+    /// When installing `ws2812b::program()`, every word written to a tx becomes
+    /// one pixel on the strip. See `ws2812b::config` and `ColorOrder::pack`.
+    ///
     /// Returns a tuple with the tx and rx for each state machine.
     pub fn install_program<const NUM: usize>(
         &mut self, program: Program<32>,
-        pins: [(u8, u8); NUM],
-        clock_divisors: [(u16, u8); NUM],
+        configs: [StateMachineConfig; NUM],
     ) -> Result<ArrayVec<RxTx<P>, NUM>, Error> {
-        if NUM > 4 {
+        if NUM > self.free_state_machines() as usize {
             return Err(Error::TooManyStateMachinesRequested)
         }
 
-        // Install the program to the PIO block
-        let installed = self.pio.install(&program).unwrap();
+        let id = self.load_program(&program)?;
 
         let mut rxtxs: ArrayVec<_, NUM> = ArrayVec::new();
 
-        if NUM >= 1 {
-            // Install to state machine 0
-            let pins = pins[0];
-            let clock_divisor = clock_divisors[0];
-
-            let Ok((rx, tx)) = self.sm0.program(&installed, pins, clock_divisor) else {
-                return Err(Error::BadStateMachineProgramming)
-            };
+        for config in configs {
+            // There are enough free state machines, checked above
+            let handle = self.claim(Select::Any)?;
+            let rxtx = self.program(&handle, id, &config);
 
-            rxtxs.push(RxTx::SM0(rx, tx));
-        }
+            match rxtx {
+                Ok(rxtx) => rxtxs.push(rxtx),
+                Err(error) => {
+                    // Leave things the way they were. Uninstalling gives back
+                    // the load, unless nothing was programmed to run it yet.
+                    let programmed = !rxtxs.is_empty();
 
-        if NUM >= 2 {
-            // Install to state machine 1
-            let pins = pins[1];
-            let clock_divisor = clock_divisors[1];
+                    self.release(handle, None)?;
+                    self.unininstall_program(rxtxs).map_err(|_| Error::BadStateMachineProgramming)?;
 
-            let Ok((rx, tx)) = self.sm1.program(&installed, pins, clock_divisor) else {
-                return Err(Error::BadStateMachineProgramming)
-            };
+                    if !programmed {
+                        let _ = self.unload_program(id);
+                    }
 
-            rxtxs.push(RxTx::SM1(rx, tx));
+                    return Err(error)
+                },
+            }
         }
 
-        if NUM >= 3 {
-            // Install to state machine 2
-            let pins = pins[2];
-            let clock_divisor = clock_divisors[2];
+        Ok(rxtxs)
+    }
 
-            let Ok((rx, tx)) = self.sm2.program(&installed, pins, clock_divisor) else {
-                return Err(Error::BadStateMachineProgramming)
-            };
+    /// Installs a parallel program to the first state machine that hasn't
+    /// been claimed, and hands it the pins
+    ///
+    /// * `pins` - Consecutive pins from `Hardware::take_pin`, one per strip and
+    ///   at most 8, lowest first. They're connected to this block with
+    ///   `connect_pin`.
+    /// * `clock_divisor` - See `ClockDivisor::from_frequency`
+    ///
+    /// When installing `parallel::program()`, every word written to the tx
+    /// holds 4 bit planes. Use `parallel::transpose` to build them.
+    ///
+    /// Returns the state machine's handle, the program's id, its rx and tx,
+    /// and the connected pins. Once it's released and unloaded, the pins go
+    /// back through `disconnect_pin`. If anything fails, the pins are handed
+    /// back as they were along with the error.
+    pub fn install_parallel_program(
+        &mut self,
+        program: Program<32>,
+        pins: ArrayVec<DynPin, {parallel::MAX_STRIPS}>,
+        clock_divisor: ClockDivisor,
+    ) -> Result<ParallelInstall<P>, (ArrayVec<DynPin, {parallel::MAX_STRIPS}>, Error)> {
+        let Some(base) = pins.first().map(|pin| pin.id().num) else {
+            return Err((pins, Error::BadStateMachineProgramming))
+        };
 
-            rxtxs.push(RxTx::SM2(rx, tx));
+        if pins.iter().zip(base..).any(|(pin, num)| pin.id().num != num) {
+            return Err((pins, Error::BadStateMachineProgramming))
         }
 
-        if NUM >= 4 {
-            // Install to state machine 3
-            let pins = pins[3];
-            let clock_divisor = clock_divisors[3];
+        let handle = match self.claim(Select::Any) {
+            Ok(handle) => handle,
+            Err(error) => return Err((pins, error)),
+        };
 
-            let Ok((rx, tx)) = self.sm3.program(&installed, pins, clock_divisor) else {
-                return Err(Error::BadStateMachineProgramming)
-            };
+        let config = parallel::config((base, pins.len() as u8), clock_divisor);
+        let installed = self.load_program(&program).and_then(|id| {
+            match self.program(&handle, id, &config) {
+                Ok(rxtx) => Ok((id, rxtx)),
+                Err(error) => {
+                    let _ = self.unload_program(id);
+                    Err(error)
+                },
+            }
+        });
 
-            rxtxs.push(RxTx::SM3(rx, tx));
+        match installed {
+            Ok((id, rxtx)) => Ok((handle, id, rxtx, pins.into_iter().map(connect_pin::<P>).collect())),
+            Err(error) => {
+                // Nothing was programmed, so this can't fail
+                let _ = self.release(handle, None);
+                Err((pins, error))
+            },
         }
-
-        self.in_use = true;
-        Ok(rxtxs)
     }
 
     /// Uninstalls a program
     ///
     /// * `rxtx` - The same rx and tx channels returned by install_program
     ///
-    /// Returns a tuple with the tx and rx for each state machine.
+    /// Each state machine is stopped if it's still running, and released so
+    /// it can be claimed again. Then the load `install_program` took is given
+    /// back, which frees the program's instruction memory if nothing else has
+    /// it loaded.
     pub fn unininstall_program<const NUM: usize>(
         &mut self,
         rxtxs: ArrayVec<RxTx<P>, NUM>
     ) -> Result<(), state_machine::Error> {
+        let mut ids: ArrayVec<ProgramId, NUM> = ArrayVec::new();
+
         for rxtx in rxtxs {
-            match rxtx {
-                RxTx::SM0(rx, tx) => self.sm0.uninstall(rx, tx)?,
-                RxTx::SM1(rx, tx) => self.sm1.uninstall(rx, tx)?,
-                RxTx::SM2(rx, tx) => self.sm2.uninstall(rx, tx)?,
-                RxTx::SM3(rx, tx) => self.sm3.uninstall(rx, tx)?,
+            let index = rxtx.index();
+
+            if let Some(id) = self.uninstall_state_machine(rxtx)? {
+                if !ids.contains(&id) {
+                    ids.push(id);
+                }
             }
+
+            self.claimed &= !(1 << index);
         }
 
-        self.in_use = false;
-        Ok(())
-    }
+        for id in ids {
+            let _ = self.unload_program(id);
+        }
 
-    /// Returns whether or not this pio is in use
-    pub fn in_use(&self) -> bool {
-        self.in_use
+        Ok(())
     }
 
     /// Starts the state machines
+    ///
+    /// Everything that's been programmed but isn't running yet starts on the
+    /// same cycle, with its clock divider restarted. See `start_synchronized`.
     pub fn start(&mut self) -> Result<(), state_machine::Error> {
-        if self.sm0.is_initialized() {
+        let mask = self.initialized_mask() & !self.running_mask();
+        self.start_synchronized(mask)
+    }
+
+    /// Starts several state machines on the same cycle
+    ///
+    /// Their clock dividers are restarted at the same time, so machines
+    /// running at the same divisor stay in phase with each other.
+    ///
+    /// * `mask` - Bit n set starts state machine n. Each of these has to be
+    ///   programmed and stopped.
+    pub fn start_synchronized(&mut self, mask: u8) -> Result<(), state_machine::Error> {
+        self.check_startable(mask)?;
+
+        critical_section::with(|_| {
+            let ctrl = registers::<P>().ctrl();
+            let bits = ctrl.read().bits() | start_bits(mask);
+
+            ctrl.write(|w| unsafe { w.bits(bits) });
+        });
+
+        self.mark_started(mask)
+    }
+
+    /// Restarts the clock dividers of several state machines on the same cycle
+    ///
+    /// This brings machines that are already running back into phase without
+    /// stopping them.
+    ///
+    /// * `mask` - Bit n set restarts the divider of state machine n
+    pub fn restart_clock_dividers(&mut self, mask: u8) {
+        critical_section::with(|_| {
+            let ctrl = registers::<P>().ctrl();
+            let bits = ctrl.read().bits() | clock_divider_restart_bits(mask);
+
+            ctrl.write(|w| unsafe { w.bits(bits) });
+        });
+    }
+
+    /// Bit mask of the state machines that have been programmed
+    pub fn initialized_mask(&self) -> u8 {
+        self.sm0.is_initialized() as u8
+            | (self.sm1.is_initialized() as u8) << 1
+            | (self.sm2.is_initialized() as u8) << 2
+            | (self.sm3.is_initialized() as u8) << 3
+    }
+
+    /// Bit mask of the state machines that are running
+    pub fn running_mask(&self) -> u8 {
+        self.sm0.is_running() as u8
+            | (self.sm1.is_running() as u8) << 1
+            | (self.sm2.is_running() as u8) << 2
+            | (self.sm3.is_running() as u8) << 3
+    }
+
+    /// Makes sure every state machine in a mask is ready to start
+    fn check_startable(&self, mask: u8) -> Result<(), state_machine::Error> {
+        let mask = mask & 0xf;
+        let stopped = self.initialized_mask() & !self.running_mask();
+
+        if mask & !stopped != 0 {
+            return Err(state_machine::Error::FailedToStart)
+        }
+
+        Ok(())
+    }
+
+    /// Moves the state machines in a mask over to running after they've been
+    /// started through the registers
+    ///
+    /// The enable bits are already set, so setting them again doesn't change
+    /// anything on the hardware.
+    fn mark_started(&mut self, mask: u8) -> Result<(), state_machine::Error> {
+        if mask & 1 != 0 {
             self.sm0.start()?;
         }
-        if self.sm1.is_initialized() {
+        if mask & 1 << 1 != 0 {
             self.sm1.start()?;
         }
-        if self.sm2.is_initialized() {
+        if mask & 1 << 2 != 0 {
             self.sm2.start()?;
         }
-        if self.sm3.is_initialized() {
+        if mask & 1 << 3 != 0 {
             self.sm3.start()?;
         }
 
@@ -158,19 +540,436 @@ impl<P: PIOExt> Pio<P> {
 
     /// Stops the state machines
     pub fn stop(&mut self) -> Result<(), state_machine::Error> {
-        if self.sm0.is_initialized() {
+        let mask = self.running_mask();
+        self.stop_state_machines(mask)
+    }
+
+    /// Stops the state machines in a mask that are running
+    ///
+    /// * `mask` - Bit n set stops state machine n
+    pub fn stop_state_machines(&mut self, mask: u8) -> Result<(), state_machine::Error> {
+        let mask = mask & self.running_mask();
+
+        if mask & 1 != 0 {
             self.sm0.stop()?;
         }
-        if self.sm1.is_initialized() {
+        if mask & 1 << 1 != 0 {
             self.sm1.stop()?;
         }
-        if self.sm2.is_initialized() {
+        if mask & 1 << 2 != 0 {
             self.sm2.stop()?;
         }
-        if self.sm3.is_initialized() {
+        if mask & 1 << 3 != 0 {
             self.sm3.stop()?;
         }
 
         Ok(())
     }
+
+    /// Stops and uninstalls a single state machine
+    ///
+    /// Returns the id of the program it was running.
+    fn uninstall_state_machine(&mut self, rxtx: RxTx<P>) -> Result<Option<ProgramId>, state_machine::Error> {
+        let index = rxtx.index();
+
+        match rxtx {
+            RxTx::SM0(rx, tx) => self.sm0.uninstall(rx, tx)?,
+            RxTx::SM1(rx, tx) => self.sm1.uninstall(rx, tx)?,
+            RxTx::SM2(rx, tx) => self.sm2.uninstall(rx, tx)?,
+            RxTx::SM3(rx, tx) => self.sm3.uninstall(rx, tx)?,
+        }
+
+        let Some(loaded) = self.programs.iter_mut().find(|loaded| loaded.users & 1 << index != 0) else {
+            return Ok(None)
+        };
+
+        loaded.users &= !(1 << index);
+
+        Ok(Some(ProgramId {offset: loaded.installed.offset(), generation: loaded.generation}))
+    }
+
+    /// Finds a loaded program with the same code
+    fn find_program(&self, program: &Program<32>) -> Option<usize> {
+        self.programs.iter().position(|loaded| {
+            let offset = loaded.installed.offset();
+
+            let same_place = program.origin.is_none_or(|origin| origin == offset);
+            let same_code = loaded.code == program.code && loaded.wrap == program.wrap;
+            let same_side_set = (loaded.side_set.optional(), loaded.side_set.bits(), loaded.side_set.pindirs())
+                == (program.side_set.optional(), program.side_set.bits(), program.side_set.pindirs());
+
+            same_place && same_code && same_side_set
+        })
+    }
+
+    fn program_index(&self, id: ProgramId) -> Option<usize> {
+        self.programs.iter().position(|loaded| {
+            loaded.installed.offset() == id.offset && loaded.generation == id.generation
+        })
+    }
+}
+
+/// Starts state machines on both PIO blocks together
+///
+/// The two blocks have separate control registers, so they're written back to
+/// back with nothing else in between. Machines on the same block start on the
+/// same cycle, and the two blocks start within a couple of cycles of each
+/// other. See `Pio::start_synchronized`.
+///
+/// * `mask0` - Bit n set starts state machine n on PIO0
+/// * `mask1` - Bit n set starts state machine n on PIO1
+pub fn start_synchronized(
+    pio0: &mut Pio<PIO0>,
+    mask0: u8,
+    pio1: &mut Pio<PIO1>,
+    mask1: u8,
+) -> Result<(), state_machine::Error> {
+    pio0.check_startable(mask0)?;
+    pio1.check_startable(mask1)?;
+
+    critical_section::with(|_| {
+        let ctrl0 = registers::<PIO0>().ctrl();
+        let ctrl1 = registers::<PIO1>().ctrl();
+
+        // Work out both values first so the writes are as close as they can be
+        let bits0 = ctrl0.read().bits() | start_bits(mask0);
+        let bits1 = ctrl1.read().bits() | start_bits(mask1);
+
+        ctrl0.write(|w| unsafe { w.bits(bits0) });
+        ctrl1.write(|w| unsafe { w.bits(bits1) });
+    });
+
+    pio0.mark_started(mask0)?;
+    pio1.mark_started(mask1)?;
+
+    Ok(())
+}
+
+/// A pin that has been handed over to a PIO block, see `connect_pin`
+pub type PioPin = Pin<DynPinId, DynFunction, PullDown>;
+
+/// What `Pio::install_parallel_program` hands back
+pub type ParallelInstall<P> = (StateMachineHandle, ProgramId, RxTx<P>, ArrayVec<PioPin, {parallel::MAX_STRIPS}>);
+
+/// Hands a pin over to a PIO block
+///
+/// The pin is switched to the block's function, with a fast slew rate and
+/// 12mA of drive so the edges stay sharp on long data lines. Its direction is
+/// left to the state machine, see `StateMachineConfig::output_pins`.
+pub fn connect_pin<P: PIOExt>(pin: DynPin) -> PioPin {
+    let mut pin = pin.into_function::<DynFunction>();
+
+    let function = match P::id() {
+        0 => DynFunction::Pio0,
+        _ => DynFunction::Pio1,
+    };
+
+    // Every bank 0 pin can be driven by either block, so this can't fail
+    let _ = pin.try_set_function(function);
+    pin.set_drive_strength(OutputDriveStrength::TwelveMilliAmps);
+    pin.set_slew_rate(OutputSlewRate::Fast);
+
+    pin
+}
+
+/// Takes a pin back from a PIO block
+///
+/// The pin goes back to the null function, with the reset drive strength and
+/// slew rate, ready for `Hardware::return_pin`.
+pub fn disconnect_pin(pin: PioPin) -> DynPin {
+    let mut pin = pin;
+    pin.set_drive_strength(OutputDriveStrength::FourMilliAmps);
+    pin.set_slew_rate(OutputSlewRate::Slow);
+
+    match pin.try_into_function() {
+        Ok(pin) => pin,
+        // Every pin has a null function
+        Err(_) => unreachable!(),
+    }
+}
+
+/// Gives a state machine back to whichever block its rx and tx came from
+///
+/// This saves matching on the rx and tx enums to find the right block. See
+/// `Pio::release`.
+pub fn release(
+    pio0: Option<&mut Pio<PIO0>>,
+    pio1: Option<&mut Pio<PIO1>>,
+    handle: StateMachineHandle,
+    rx: rx::Rx,
+    tx: tx::Tx,
+) -> Result<(), Error> {
+    use rx::Rx;
+    use tx::Tx;
+
+    let pio0 = pio0.ok_or(Error::MissingPIO);
+    let pio1 = pio1.ok_or(Error::MissingPIO);
+
+    match (rx, tx) {
+        (Rx::PIO0SM0(rx), Tx::PIO0SM0(tx)) => pio0?.release(handle, Some(RxTx::SM0(rx, tx))),
+        (Rx::PIO0SM1(rx), Tx::PIO0SM1(tx)) => pio0?.release(handle, Some(RxTx::SM1(rx, tx))),
+        (Rx::PIO0SM2(rx), Tx::PIO0SM2(tx)) => pio0?.release(handle, Some(RxTx::SM2(rx, tx))),
+        (Rx::PIO0SM3(rx), Tx::PIO0SM3(tx)) => pio0?.release(handle, Some(RxTx::SM3(rx, tx))),
+        (Rx::PIO1SM0(rx), Tx::PIO1SM0(tx)) => pio1?.release(handle, Some(RxTx::SM0(rx, tx))),
+        (Rx::PIO1SM1(rx), Tx::PIO1SM1(tx)) => pio1?.release(handle, Some(RxTx::SM1(rx, tx))),
+        (Rx::PIO1SM2(rx), Tx::PIO1SM2(tx)) => pio1?.release(handle, Some(RxTx::SM2(rx, tx))),
+        (Rx::PIO1SM3(rx), Tx::PIO1SM3(tx)) => pio1?.release(handle, Some(RxTx::SM3(rx, tx))),
+        _ => Err(Error::WrongStateMachine),
+    }
+}
+
+/// CTRL bits that enable the state machines in a mask and restart their
+/// clock dividers
+fn start_bits(mask: u8) -> u32 {
+    (mask as u32 & 0xf) | clock_divider_restart_bits(mask)
+}
+
+/// CTRL bits that restart the clock dividers of the state machines in a mask
+fn clock_divider_restart_bits(mask: u8) -> u32 {
+    (mask as u32 & 0xf) << 8
+}
+
+fn registers<P: PIOExt>() -> &'static pac::pio0::RegisterBlock {
+    unsafe {
+        match P::id() {
+            0 => &*PIO0::ptr(),
+            _ => &*PIO1::ptr(),
+        }
+    }
+}
+
+#[cfg(all(test, feature = "host"))]
+mod tests {
+    use super::*;
+
+    use ::pio::{Assembler, SetDestination};
+
+    use crate::clock_divisor::ClockDivisor;
+    use crate::hardware::Hardware;
+    use crate::mock;
+    use crate::{parallel, ws2812b};
+
+    const CONFIG: StateMachineConfig = StateMachineConfig::new(ClockDivisor::new(1, 0));
+
+    fn pio0() -> &'static mut Pio<PIO0> {
+        Hardware::get().unwrap().get_pio0_mut().unwrap()
+    }
+
+    #[test]
+    fn releasing_keeps_the_program_loaded() {
+        Hardware::init(12_000_000);
+        let pio = pio0();
+
+        let id = pio.load_program(&ws2812b::program()).unwrap();
+        let handle = pio.claim(Select::Any).unwrap();
+        let rxtx = pio.program(&handle, id, &CONFIG).unwrap();
+
+        assert!(matches!(pio.unload_program(id), Err(Error::ProgramInUse)));
+
+        pio.release(handle, Some(rxtx)).unwrap();
+        assert!(pio.free_instructions() < 32);
+
+        pio.unload_program(id).unwrap();
+        assert_eq!(pio.free_instructions(), 32);
+    }
+
+    #[test]
+    fn every_load_needs_its_own_unload() {
+        Hardware::init(12_000_000);
+        let pio = pio0();
+
+        let first = pio.load_program(&ws2812b::program()).unwrap();
+        let second = pio.load_program(&ws2812b::program()).unwrap();
+        let free = pio.free_instructions();
+
+        assert_eq!(first, second);
+        assert_eq!(free as usize, 32 - ws2812b::program().code.len());
+
+        // The other load is still running a strip
+        let handle = pio.claim(Select::Any).unwrap();
+        let rxtx = pio.program(&handle, second, &CONFIG).unwrap();
+
+        pio.unload_program(first).unwrap();
+        assert_eq!(pio.free_instructions(), free);
+
+        pio.release(handle, Some(rxtx)).unwrap();
+        pio.unload_program(second).unwrap();
+        assert_eq!(pio.free_instructions(), 32);
+        assert!(matches!(pio.unload_program(second), Err(Error::NoSuchProgram)));
+    }
+
+    #[test]
+    fn stale_ids_are_rejected() {
+        Hardware::init(12_000_000);
+        let pio = pio0();
+
+        let stale = pio.load_program(&ws2812b::program()).unwrap();
+        pio.unload_program(stale).unwrap();
+
+        // Lands where the unloaded program was
+        let id = pio.load_program(&parallel::program()).unwrap();
+        assert_eq!(id.offset, stale.offset);
+        assert_ne!(id, stale);
+
+        let handle = pio.claim(Select::Any).unwrap();
+        assert!(matches!(pio.program(&handle, stale, &CONFIG), Err(Error::NoSuchProgram)));
+        assert!(matches!(pio.unload_program(stale), Err(Error::NoSuchProgram)));
+
+        pio.program(&handle, id, &CONFIG).unwrap();
+    }
+
+    #[test]
+    fn uninstalling_gives_back_the_load() {
+        Hardware::init(12_000_000);
+        let pio = pio0();
+
+        let other = pio.load_program(&parallel::program()).unwrap();
+        let rxtxs = pio.install_program(parallel::program(), [CONFIG; 2]).unwrap();
+        assert_eq!(pio.claimed_mask(), 0b0011);
+
+        pio.unininstall_program(rxtxs).unwrap();
+        assert_eq!(pio.claimed_mask(), 0);
+        assert!(pio.free_instructions() < 32);
+
+        pio.unload_program(other).unwrap();
+        assert_eq!(pio.free_instructions(), 32);
+    }
+
+    #[test]
+    fn claim_picks_free_state_machines() {
+        Hardware::init(12_000_000);
+        let pio = pio0();
+
+        let first = pio.claim(Select::Index(1)).unwrap();
+        assert!(matches!(pio.claim(Select::Index(1)), Err(Error::StateMachineInUse)));
+        assert!(matches!(pio.claim(Select::Index(4)), Err(Error::TooManyStateMachinesRequested)));
+
+        // Any skips over the one that's taken
+        let masks: Vec<u8> = (0..3).map(|_| pio.claim(Select::Any).unwrap().mask()).collect();
+        assert_eq!(masks, [0b0001, 0b0100, 0b1000]);
+        assert_eq!(pio.free_state_machines(), 0);
+        assert!(matches!(pio.claim(Select::Any), Err(Error::NoFreeStateMachine)));
+
+        pio.release(first, None).unwrap();
+        assert_eq!(pio.claimed_mask(), 0b1101);
+        assert_eq!(pio.claim(Select::Any).unwrap().mask(), 0b0010);
+    }
+
+    #[test]
+    fn release_needs_the_matching_rxtx() {
+        Hardware::init(12_000_000);
+        let pio = pio0();
+
+        let id = pio.load_program(&ws2812b::program()).unwrap();
+        let first = pio.claim(Select::Index(0)).unwrap();
+        let second = pio.claim(Select::Index(1)).unwrap();
+        let rxtx = pio.program(&second, id, &CONFIG).unwrap();
+
+        assert!(matches!(pio.release(first, Some(rxtx)), Err(Error::WrongStateMachine)));
+        assert_eq!(pio.claimed_mask(), 0b0011);
+    }
+
+    #[test]
+    fn programmed_state_machines_need_their_rxtx_back() {
+        Hardware::init(12_000_000);
+        let pio = pio0();
+
+        let id = pio.load_program(&ws2812b::program()).unwrap();
+        let handle = pio.claim(Select::Index(2)).unwrap();
+        let _rxtx = pio.program(&handle, id, &CONFIG).unwrap();
+
+        assert!(matches!(pio.release(handle, None), Err(Error::StillProgrammed)));
+        assert_eq!(pio.claimed_mask(), 0b0100);
+        assert!(matches!(pio.claim(Select::Index(2)), Err(Error::StateMachineInUse)));
+    }
+
+    #[test]
+    fn programs_that_dont_fit_are_refused() {
+        Hardware::init(12_000_000);
+        let pio = pio0();
+
+        let strip = pio.load_program(&ws2812b::program()).unwrap();
+        let parallel = pio.load_program(&parallel::program()).unwrap();
+        let used = 32 - pio.free_instructions();
+
+        assert_ne!(strip, parallel);
+        assert_eq!(used as usize, ws2812b::program().code.len() + parallel::program().code.len());
+
+        // One instruction more than is left
+        let mut a = Assembler::<32>::new();
+        for _ in 0..=pio.free_instructions() {
+            a.set(SetDestination::X, 0);
+        }
+
+        assert!(matches!(pio.load_program(&a.assemble_program()), Err(Error::NoSpace)));
+        assert_eq!(32 - pio.free_instructions(), used);
+    }
+
+    #[test]
+    fn parallel_installs_connect_their_pins() {
+        Hardware::init(12_000_000);
+        let hardware = Hardware::get().unwrap();
+        let pins = (2..5).map(|num| hardware.take_pin(num).unwrap()).collect();
+
+        let (handle, id, rxtx, pins) = pio0()
+            .install_parallel_program(parallel::program(), pins, ClockDivisor::new(1, 0))
+            .map_err(|(_, error)| error)
+            .unwrap();
+
+        assert_eq!(handle.index(), 0);
+        assert_eq!(mock::pio::record(0, 0).config.unwrap().out_pins, (2, 3));
+        assert!(pins.iter().all(|pin| pin.function() == DynFunction::Pio0));
+
+        pio0().release(handle, Some(rxtx)).unwrap();
+        pio0().unload_program(id).unwrap();
+        for pin in pins {
+            hardware.return_pin(disconnect_pin(pin)).unwrap();
+        }
+    }
+
+    #[test]
+    fn parallel_pins_have_to_be_consecutive() {
+        Hardware::init(12_000_000);
+        let hardware = Hardware::get().unwrap();
+        let pins = [2, 4].into_iter().map(|num| hardware.take_pin(num).unwrap()).collect();
+
+        let Err((pins, Error::BadStateMachineProgramming)) =
+            pio0().install_parallel_program(parallel::program(), pins, ClockDivisor::new(1, 0))
+        else {
+            panic!("pins 2 and 4 aren't consecutive");
+        };
+
+        assert_eq!(pins.len(), 2);
+        assert_eq!(pio0().claimed_mask(), 0);
+        assert_eq!(pio0().free_instructions(), 32);
+    }
+
+    #[test]
+    fn start_synchronized_sets_the_ctrl_bits() {
+        assert_eq!(start_bits(0b0101), 0x0505);
+        assert_eq!(clock_divider_restart_bits(0xff), 0x0f00);
+
+        Hardware::init(12_000_000);
+        let pio = pio0();
+
+        let id = pio.load_program(&ws2812b::program()).unwrap();
+
+        for index in 0..3 {
+            let handle = pio.claim(Select::Index(index)).unwrap();
+            pio.program(&handle, id, &CONFIG).unwrap();
+        }
+
+        pio.start_synchronized(0b0101).unwrap();
+        assert_eq!(mock::pio::enabled_mask(0), 0b0101);
+        assert_eq!(pio.running_mask(), 0b0101);
+
+        // Machines that are already running or were never programmed can't
+        // be started
+        assert!(matches!(pio.start_synchronized(0b0001), Err(state_machine::Error::FailedToStart)));
+        assert!(matches!(pio.start_synchronized(0b1000), Err(state_machine::Error::FailedToStart)));
+        assert_eq!(mock::pio::enabled_mask(0), 0b0101);
+
+        pio.start().unwrap();
+        assert_eq!(mock::pio::enabled_mask(0), 0b0111);
+    }
 }
@@ -0,0 +1,953 @@
+//! Runs PIO programs on the host, one state machine cycle at a time
+//!
+//! This decodes the same `Program` that gets loaded onto the hardware, so a
+//! program can be checked without a logic analyzer. Words are fed in through
+//! the tx FIFO, and the level of every pin is recorded after each cycle.
+//!
+//! Only a single state machine is simulated. Its IRQ flags can be set and
+//! cleared from outside to stand in for the other state machines or the CPU,
+//! and `Config::index` says which state machine it is for relative IRQs.
+//! The clock divisor isn't modelled, a cycle is always one state machine clock.
+
+use alloc::collections::VecDeque;
+use alloc::vec::Vec;
+
+use ::pio::{
+    InSource, Instruction, InstructionOperands, JmpCondition, MovDestination, MovOperation, MovSource,
+    OutDestination, Program, SetDestination, SideSet, WaitSource,
+};
+
+#[derive(Debug)]
+pub enum Error {
+    /// The program doesn't fit in instruction memory at its origin
+    ProgramTooLong,
+    /// A word in the program isn't a valid instruction
+    InvalidInstruction(u16),
+}
+
+/// Which end of a shift register bits come out of or go in at
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ShiftDirection {
+    Left,
+    Right,
+}
+
+/// What `mov x, status` reads as
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Status {
+    /// All ones while the tx FIFO holds fewer than this many words
+    TxLessThan(u8),
+    /// All ones while the rx FIFO holds fewer than this many words
+    RxLessThan(u8),
+}
+
+/// How to set up the simulated state machine
+///
+/// This mirrors `StateMachineConfig`, except that pins are plain values since
+/// there's no hardware default to fall back on.
+#[derive(Clone, Copy, Debug)]
+pub struct Config {
+    /// Base and count of the pins driven by `set`
+    pub set_pins: (u8, u8),
+    /// Base and count of the pins driven by `out` and `mov pins`
+    pub out_pins: (u8, u8),
+    /// First pin driven by side-set. The count comes from the program.
+    pub side_set_base: u8,
+    /// First pin read by `in` and `wait pin`
+    pub in_pin_base: u8,
+    /// Pin tested by `jmp pin`
+    pub jmp_pin: u8,
+    /// Pins that start out as outputs, as a mask
+    pub output_pins: u32,
+    pub autopull: bool,
+    /// Number of bits shifted out before pulling the next word, from 1 to 32
+    pub pull_threshold: u8,
+    pub out_shift_direction: ShiftDirection,
+    pub autopush: bool,
+    /// Number of bits shifted in before pushing a word, from 1 to 32
+    pub push_threshold: u8,
+    pub in_shift_direction: ShiftDirection,
+    /// Which state machine in the block this is, from 0 to 3. Relative IRQs
+    /// are offset by it.
+    pub index: u8,
+    /// Depth of the tx FIFO, 8 when joined
+    pub tx_depth: usize,
+    /// Depth of the rx FIFO, 8 when joined
+    pub rx_depth: usize,
+    pub status: Status,
+}
+
+impl Config {
+    /// A config with every pin at 0 and the hardware's reset values for
+    /// everything else
+    pub const fn new() -> Config {
+        Config {
+            set_pins: (0, 0),
+            out_pins: (0, 0),
+            side_set_base: 0,
+            in_pin_base: 0,
+            jmp_pin: 0,
+            output_pins: 0,
+            autopull: false,
+            pull_threshold: 32,
+            out_shift_direction: ShiftDirection::Right,
+            autopush: false,
+            push_threshold: 32,
+            in_shift_direction: ShiftDirection::Right,
+            index: 0,
+            tx_depth: 4,
+            rx_depth: 4,
+            status: Status::TxLessThan(0),
+        }
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config::new()
+    }
+}
+
+/// A single simulated state machine with its program loaded
+pub struct Simulator {
+    /// All of instruction memory, with the program at its origin and the
+    /// rest left as zeroes
+    code: [u16; 32],
+    side_set: SideSet,
+    wrap_source: u8,
+    wrap_target: u8,
+    config: Config,
+
+    pc: u8,
+    x: u32,
+    y: u32,
+    osr: u32,
+    /// Bits shifted out of the OSR since it was last filled
+    osr_count: u8,
+    isr: u32,
+    /// Bits shifted into the ISR since it was last emptied
+    isr_count: u8,
+    /// Cycles left of the last instruction's delay
+    delay: u8,
+    /// Instruction written by `out exec` or `mov exec`, run next instead of
+    /// the one at `pc`
+    exec: Option<u16>,
+
+    tx: VecDeque<u32>,
+    rx: VecDeque<u32>,
+    /// Sticky flag set whenever a pull stalls on an empty tx FIFO
+    tx_stalled: bool,
+    irq: u8,
+    /// Set while an `irq wait` is waiting for its flag to be cleared
+    irq_waiting: bool,
+
+    pins: u32,
+    pindirs: u32,
+    /// Levels driven onto input pins from outside
+    inputs: u32,
+
+    cycle: u64,
+    trace: Vec<u32>,
+}
+
+impl Simulator {
+    /// Loads a program at its origin, or at offset 0 if it doesn't have one
+    ///
+    /// Jumps are relocated the same way as when the program is installed on
+    /// the hardware.
+    pub fn new<const N: usize>(program: &Program<N>, config: Config) -> Result<Simulator, Error> {
+        let offset = program.origin.unwrap_or(0);
+        if offset as usize + program.code.len() > 32 {
+            return Err(Error::ProgramTooLong)
+        }
+
+        let mut code = [0; 32];
+        for (slot, &word) in code[offset as usize..].iter_mut().zip(&program.code) {
+            // Decode everything up front so a bad word is caught here rather
+            // than part way through a run
+            let instruction = Instruction::decode(word, program.side_set).ok_or(Error::InvalidInstruction(word))?;
+
+            *slot = match instruction.operands {
+                InstructionOperands::JMP {..} => (word & !0b11111) | ((word + offset as u16) & 0b11111),
+                _ => word,
+            };
+        }
+
+        Ok(Simulator {
+            code,
+            side_set: program.side_set,
+            wrap_source: program.wrap.source + offset,
+            wrap_target: program.wrap.target + offset,
+            config,
+            pc: offset,
+            x: 0,
+            y: 0,
+            // The OSR starts out empty, so the first out autopulls
+            osr: 0,
+            osr_count: 32,
+            isr: 0,
+            isr_count: 0,
+            delay: 0,
+            exec: None,
+            tx: VecDeque::new(),
+            rx: VecDeque::new(),
+            tx_stalled: false,
+            irq: 0,
+            irq_waiting: false,
+            pins: 0,
+            pindirs: config.output_pins,
+            inputs: 0,
+            cycle: 0,
+            trace: Vec::new(),
+        })
+    }
+
+    /// Pushes a word into the tx FIFO
+    ///
+    /// Returns `false` if the FIFO is full.
+    pub fn write(&mut self, word: u32) -> bool {
+        if self.tx.len() >= self.config.tx_depth {
+            return false
+        }
+
+        self.tx.push_back(word);
+        true
+    }
+
+    /// Takes the next word out of the rx FIFO
+    pub fn read(&mut self) -> Option<u32> {
+        self.rx.pop_front()
+    }
+
+    /// Number of words waiting in the tx FIFO
+    pub fn tx_level(&self) -> usize {
+        self.tx.len()
+    }
+
+    /// Number of words waiting in the rx FIFO
+    pub fn rx_level(&self) -> usize {
+        self.rx.len()
+    }
+
+    /// Checks if a pull has stalled on an empty tx FIFO since the flag was
+    /// last cleared
+    pub fn has_stalled(&self) -> bool {
+        self.tx_stalled
+    }
+
+    pub fn clear_stalled_flag(&mut self) {
+        self.tx_stalled = false;
+    }
+
+    /// Drives a pin from outside, for the program to read with `in`, `wait`,
+    /// and `jmp pin`
+    ///
+    /// Only shows up while the state machine isn't driving the pin itself.
+    pub fn set_input(&mut self, pin: u8, high: bool) {
+        set_bit(&mut self.inputs, pin, high);
+    }
+
+    /// The IRQ flags, one bit each
+    pub fn irq_flags(&self) -> u8 {
+        self.irq
+    }
+
+    pub fn set_irq(&mut self, index: u8) {
+        self.irq |= 1 << (index & 7);
+    }
+
+    pub fn clear_irq(&mut self, index: u8) {
+        self.irq &= !(1 << (index & 7));
+    }
+
+    pub fn pc(&self) -> u8 {
+        self.pc
+    }
+
+    pub fn x(&self) -> u32 {
+        self.x
+    }
+
+    pub fn y(&self) -> u32 {
+        self.y
+    }
+
+    /// Number of cycles run so far
+    pub fn cycle(&self) -> u64 {
+        self.cycle
+    }
+
+    /// The level of every pin as seen from outside
+    ///
+    /// Pins that aren't outputs read as whatever `set_input` last drove them
+    /// to.
+    pub fn pins(&self) -> u32 {
+        (self.pins & self.pindirs) | (self.inputs & !self.pindirs)
+    }
+
+    pub fn pindirs(&self) -> u32 {
+        self.pindirs
+    }
+
+    /// Pin levels after every cycle run so far, in order
+    pub fn trace(&self) -> &[u32] {
+        &self.trace
+    }
+
+    /// A single pin's level after every cycle run so far
+    pub fn pin_trace(&self, pin: u8) -> impl DoubleEndedIterator<Item = bool> + ExactSizeIterator + '_ {
+        self.trace.iter().map(move |&pins| pins >> (pin & 31) & 1 != 0)
+    }
+
+    /// Forgets the recorded trace, without touching the state machine
+    pub fn clear_trace(&mut self) {
+        self.trace.clear();
+    }
+
+    /// Runs a number of cycles
+    pub fn run(&mut self, cycles: u64) {
+        for _ in 0..cycles {
+            self.step();
+        }
+    }
+
+    /// Runs until the state machine stalls on an empty tx FIFO, or gives up
+    /// after `max_cycles`
+    ///
+    /// Returns whether or not it stalled. The stalled flag is cleared first.
+    pub fn run_until_stalled(&mut self, max_cycles: u64) -> bool {
+        self.tx_stalled = false;
+
+        for _ in 0..max_cycles {
+            self.step();
+
+            if self.tx_stalled {
+                return true
+            }
+        }
+
+        false
+    }
+
+    /// Runs a single cycle and records the pins
+    pub fn step(&mut self) {
+        self.cycle += 1;
+
+        if self.delay > 0 {
+            self.delay -= 1;
+        } else {
+            let (word, from_exec) = match self.exec.take() {
+                Some(word) => (word, true),
+                None => (self.code[self.pc as usize], false),
+            };
+
+            self.execute(word, from_exec);
+        }
+
+        self.trace.push(self.pins());
+    }
+
+    /// Runs an instruction, leaving `pc` where it should go next
+    fn execute(&mut self, word: u16, from_exec: bool) {
+        let Some(instruction) = Instruction::decode(word, self.side_set) else {
+            // Only reachable through exec, since the program was checked
+            // when it was loaded
+            return
+        };
+
+        // Side-set happens as soon as the instruction starts, even if it stalls
+        if let Some(value) = instruction.side_set {
+            let count = self.side_set.bits() - self.side_set.optional() as u8;
+
+            match self.side_set.pindirs() {
+                true => write_pins(&mut self.pindirs, self.config.side_set_base, count, value as u32),
+                false => write_pins(&mut self.pins, self.config.side_set_base, count, value as u32),
+            }
+        }
+
+        let next = match self.operate(instruction.operands) {
+            Flow::Stall => {
+                // Run the same instruction again next cycle
+                if from_exec {
+                    self.exec = Some(word);
+                }
+                return
+            },
+            Flow::Next => self.advance(from_exec),
+            Flow::Jump(address) => address % 32,
+            Flow::Exec(word) => {
+                // The delay of the instruction doing the exec is ignored
+                self.exec = Some(word);
+                self.pc = self.advance(from_exec);
+                return
+            },
+        };
+
+        self.pc = next;
+        self.delay = instruction.delay;
+    }
+
+    /// Where `pc` goes after an instruction that doesn't jump
+    fn advance(&self, from_exec: bool) -> u8 {
+        if from_exec {
+            self.pc
+        } else if self.pc == self.wrap_source {
+            self.wrap_target
+        } else {
+            (self.pc + 1) % 32
+        }
+    }
+
+    fn operate(&mut self, operands: InstructionOperands) -> Flow {
+        match operands {
+            InstructionOperands::JMP {condition, address} => {
+                let taken = match condition {
+                    JmpCondition::Always => true,
+                    JmpCondition::XIsZero => self.x == 0,
+                    JmpCondition::XDecNonZero => {
+                        let taken = self.x != 0;
+                        self.x = self.x.wrapping_sub(1);
+                        taken
+                    },
+                    JmpCondition::YIsZero => self.y == 0,
+                    JmpCondition::YDecNonZero => {
+                        let taken = self.y != 0;
+                        self.y = self.y.wrapping_sub(1);
+                        taken
+                    },
+                    JmpCondition::XNotEqualY => self.x != self.y,
+                    JmpCondition::PinHigh => self.pins() >> (self.config.jmp_pin & 31) & 1 != 0,
+                    JmpCondition::OutputShiftRegisterNotEmpty => self.osr_count < self.config.pull_threshold,
+                };
+
+                match taken {
+                    true => Flow::Jump(address),
+                    false => Flow::Next,
+                }
+            },
+            InstructionOperands::WAIT {polarity, source, index, relative} => {
+                let high = polarity != 0;
+
+                let level = match source {
+                    WaitSource::GPIO => self.pins() >> (index & 31) & 1 != 0,
+                    WaitSource::PIN => self.pins() >> ((self.config.in_pin_base + index) & 31) & 1 != 0,
+                    WaitSource::IRQ => {
+                        let index = irq_index(index, relative, self.config.index);
+                        let level = self.irq >> index & 1 != 0;
+
+                        // Waiting for a flag to be set also clears it
+                        if high && level {
+                            self.clear_irq(index);
+                        }
+
+                        level
+                    },
+                };
+
+                match level == high {
+                    true => Flow::Next,
+                    false => Flow::Stall,
+                }
+            },
+            InstructionOperands::IN {source, bit_count} => {
+                let data = match source {
+                    InSource::PINS => self.pins().rotate_right(self.config.in_pin_base as u32 & 31),
+                    InSource::X => self.x,
+                    InSource::Y => self.y,
+                    InSource::NULL => 0,
+                    InSource::ISR => self.isr,
+                    InSource::OSR => self.osr,
+                };
+
+                self.shift_in(data, bit_count);
+
+                if self.config.autopush && self.isr_count >= self.config.push_threshold {
+                    if self.rx.len() >= self.config.rx_depth {
+                        return Flow::Stall
+                    }
+
+                    self.push();
+                }
+
+                Flow::Next
+            },
+            InstructionOperands::OUT {destination, bit_count} => {
+                if self.config.autopull && self.osr_count >= self.config.pull_threshold && !self.pull() {
+                    return Flow::Stall
+                }
+
+                let count = bit_count_or_32(bit_count);
+                let data = self.shift_out(count);
+
+                match destination {
+                    OutDestination::PINS => {
+                        let (base, count) = self.config.out_pins;
+                        write_pins(&mut self.pins, base, count, data);
+                    },
+                    OutDestination::X => self.x = data,
+                    OutDestination::Y => self.y = data,
+                    OutDestination::NULL => {},
+                    OutDestination::PINDIRS => {
+                        let (base, count) = self.config.out_pins;
+                        write_pins(&mut self.pindirs, base, count, data);
+                    },
+                    OutDestination::PC => return Flow::Jump(data as u8),
+                    OutDestination::ISR => {
+                        self.isr = data;
+                        self.isr_count = count;
+                    },
+                    OutDestination::EXEC => return Flow::Exec(data as u16),
+                }
+
+                Flow::Next
+            },
+            InstructionOperands::PUSH {if_full, block} => {
+                if if_full && self.isr_count < self.config.push_threshold {
+                    return Flow::Next
+                }
+
+                if self.rx.len() >= self.config.rx_depth {
+                    // A push that doesn't block is dropped, leaving the ISR
+                    // as it was
+                    return match block {
+                        true => Flow::Stall,
+                        false => Flow::Next,
+                    }
+                }
+
+                self.push();
+                Flow::Next
+            },
+            InstructionOperands::PULL {if_empty, block} => {
+                if if_empty && self.osr_count < self.config.pull_threshold {
+                    return Flow::Next
+                }
+
+                if !self.pull() {
+                    if block {
+                        return Flow::Stall
+                    }
+
+                    // A pull that doesn't block copies X instead
+                    self.osr = self.x;
+                    self.osr_count = 0;
+                }
+
+                Flow::Next
+            },
+            InstructionOperands::MOV {destination, op, source} => {
+                let data = match source {
+                    MovSource::PINS => self.pins().rotate_right(self.config.in_pin_base as u32 & 31),
+                    MovSource::X => self.x,
+                    MovSource::Y => self.y,
+                    MovSource::NULL => 0,
+                    MovSource::STATUS => {
+                        let below = match self.config.status {
+                            Status::TxLessThan(level) => self.tx.len() < level as usize,
+                            Status::RxLessThan(level) => self.rx.len() < level as usize,
+                        };
+
+                        match below {
+                            true => u32::MAX,
+                            false => 0,
+                        }
+                    },
+                    MovSource::ISR => self.isr,
+                    MovSource::OSR => self.osr,
+                };
+
+                let data = match op {
+                    MovOperation::None => data,
+                    MovOperation::Invert => !data,
+                    MovOperation::BitReverse => data.reverse_bits(),
+                };
+
+                match destination {
+                    MovDestination::PINS => {
+                        let (base, count) = self.config.out_pins;
+                        write_pins(&mut self.pins, base, count, data);
+                    },
+                    MovDestination::X => self.x = data,
+                    MovDestination::Y => self.y = data,
+                    MovDestination::EXEC => return Flow::Exec(data as u16),
+                    MovDestination::PC => return Flow::Jump(data as u8),
+                    MovDestination::ISR => {
+                        self.isr = data;
+                        self.isr_count = 0;
+                    },
+                    MovDestination::OSR => {
+                        self.osr = data;
+                        self.osr_count = 0;
+                    },
+                }
+
+                Flow::Next
+            },
+            InstructionOperands::IRQ {clear, wait, index, relative} => {
+                let index = irq_index(index, relative, self.config.index);
+
+                if clear {
+                    self.clear_irq(index);
+                    return Flow::Next
+                }
+
+                // Waiting stalls on this same instruction until something else
+                // clears the flag, so it's only set the first time round
+                if self.irq_waiting {
+                    if self.irq >> index & 1 != 0 {
+                        return Flow::Stall
+                    }
+
+                    self.irq_waiting = false;
+                    return Flow::Next
+                }
+
+                self.set_irq(index);
+
+                match wait {
+                    true => {
+                        self.irq_waiting = true;
+                        Flow::Stall
+                    },
+                    false => Flow::Next,
+                }
+            },
+            InstructionOperands::SET {destination, data} => {
+                let (base, count) = self.config.set_pins;
+
+                match destination {
+                    SetDestination::PINS => write_pins(&mut self.pins, base, count, data as u32),
+                    SetDestination::X => self.x = data as u32,
+                    SetDestination::Y => self.y = data as u32,
+                    SetDestination::PINDIRS => write_pins(&mut self.pindirs, base, count, data as u32),
+                }
+
+                Flow::Next
+            },
+        }
+    }
+
+    /// Fills the OSR from the tx FIFO
+    ///
+    /// Returns `false` and sets the stalled flag if the FIFO is empty.
+    fn pull(&mut self) -> bool {
+        let Some(word) = self.tx.pop_front() else {
+            self.tx_stalled = true;
+            return false
+        };
+
+        self.osr = word;
+        self.osr_count = 0;
+        true
+    }
+
+    /// Moves the ISR into the rx FIFO and empties it
+    fn push(&mut self) {
+        self.rx.push_back(self.isr);
+        self.isr = 0;
+        self.isr_count = 0;
+    }
+
+    fn shift_out(&mut self, count: u8) -> u32 {
+        let count = count as u32;
+
+        let data = match self.config.out_shift_direction {
+            ShiftDirection::Left => {
+                let data = self.osr.checked_shr(32 - count).unwrap_or(0);
+                self.osr = self.osr.checked_shl(count).unwrap_or(0);
+                data
+            },
+            ShiftDirection::Right => {
+                let data = self.osr & mask(count);
+                self.osr = self.osr.checked_shr(count).unwrap_or(0);
+                data
+            },
+        };
+
+        self.osr_count = (self.osr_count + count as u8).min(32);
+        data
+    }
+
+    fn shift_in(&mut self, data: u32, bit_count: u8) {
+        let count = bit_count_or_32(bit_count) as u32;
+        let data = data & mask(count);
+
+        self.isr = match self.config.in_shift_direction {
+            ShiftDirection::Left => self.isr.checked_shl(count).unwrap_or(0) | data,
+            ShiftDirection::Right => self.isr.checked_shr(count).unwrap_or(0) | data.checked_shl(32 - count).unwrap_or(0),
+        };
+
+        self.isr_count = (self.isr_count + count as u8).min(32);
+    }
+}
+
+/// What an instruction does to the program counter
+enum Flow {
+    Next,
+    /// Run the same instruction again
+    Stall,
+    Jump(u8),
+    /// Run this instruction next, then carry on from the next one
+    Exec(u16),
+}
+
+/// A bit count of 0 means 32
+fn bit_count_or_32(bit_count: u8) -> u8 {
+    match bit_count {
+        0 => 32,
+        count => count,
+    }
+}
+
+/// Lowest `count` bits set
+fn mask(count: u32) -> u32 {
+    1u32.checked_shl(count).map_or(u32::MAX, |bit| bit - 1)
+}
+
+/// Relative IRQs add the state machine's index to the low 2 bits, modulo 4
+fn irq_index(index: u8, relative: bool, state_machine: u8) -> u8 {
+    match relative {
+        true => (index & 4) | (index.wrapping_add(state_machine) & 3),
+        false => index & 7,
+    }
+}
+
+fn set_bit(value: &mut u32, bit: u8, high: bool) {
+    match high {
+        true => *value |= 1 << (bit & 31),
+        false => *value &= !(1 << (bit & 31)),
+    }
+}
+
+/// Writes the low bits of `data` to `count` pins from `base`, wrapping past 31
+fn write_pins(pins: &mut u32, base: u8, count: u8, data: u32) {
+    for bit in 0..count {
+        set_bit(pins, base.wrapping_add(bit) & 31, data >> bit & 1 != 0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use ::pio::{Assembler, OutDestination, SetDestination};
+
+    use crate::ws2812b;
+
+    /// The WS2812B program on pin 0, pulling 24 bit pixels
+    fn ws2812b() -> Simulator {
+        let config = Config {
+            output_pins: 1,
+            autopull: true,
+            pull_threshold: 24,
+            out_shift_direction: ShiftDirection::Left,
+            tx_depth: 8,
+            ..Config::new()
+        };
+
+        Simulator::new(&ws2812b::program(), config).unwrap()
+    }
+
+    #[test]
+    fn ws2812b_holds_the_line_low_when_empty() {
+        let mut sim = ws2812b();
+
+        assert!(sim.run_until_stalled(100));
+        sim.run(100);
+
+        assert!(sim.pin_trace(0).all(|high| !high));
+    }
+
+    #[test]
+    fn ws2812b_sends_each_bit_with_the_right_high_time() {
+        let mut sim = ws2812b();
+        sim.write(0xa5_00_ff_00);
+
+        assert!(sim.run_until_stalled(1000));
+
+        // Split the trace into the high pulses, one per bit
+        let trace: Vec<bool> = sim.pin_trace(0).collect();
+        let pulses: Vec<usize> = trace
+            .split(|&high| !high)
+            .filter(|pulse| !pulse.is_empty())
+            .map(|pulse| pulse.len())
+            .collect();
+
+        let expected: Vec<usize> = (0..24)
+            .map(|bit| match 0xa5_00_ffu32 >> (23 - bit) & 1 {
+                1 => 7,
+                _ => 2,
+            })
+            .collect();
+
+        assert_eq!(pulses, expected);
+    }
+
+    #[test]
+    fn ws2812b_takes_ten_cycles_per_bit() {
+        let mut sim = ws2812b();
+        sim.write(0);
+        sim.write(0);
+
+        assert!(sim.run_until_stalled(1000));
+
+        let first_high = sim.pin_trace(0).position(|high| high).unwrap();
+        let last_high = sim.pin_trace(0).rposition(|high| high).unwrap();
+
+        // 47 bit periods from the first rising edge to the last, plus that
+        // bit's 2 cycle high time
+        assert_eq!(last_high - first_high + 1, 47 * ws2812b::CYCLES_PER_BIT as usize + 2);
+    }
+
+    #[test]
+    fn set_and_jmp_count_down() {
+        let mut a = Assembler::<32>::new();
+        let mut top = a.label();
+        let mut done = a.label();
+
+        a.set(SetDestination::X, 3);
+        a.bind(&mut top);
+        a.set(SetDestination::PINS, 1);
+        a.set(SetDestination::PINS, 0);
+        a.jmp(JmpCondition::XDecNonZero, &mut top);
+        a.bind(&mut done);
+        a.jmp(JmpCondition::Always, &mut done);
+
+        let config = Config {set_pins: (2, 1), output_pins: 1 << 2, ..Config::new()};
+        let mut sim = Simulator::new(&a.assemble_program(), config).unwrap();
+        sim.run(20);
+
+        let rising = sim.pin_trace(2).collect::<Vec<_>>().windows(2).filter(|pair| !pair[0] && pair[1]).count();
+
+        assert_eq!(rising, 4);
+        assert_eq!(sim.x(), u32::MAX);
+    }
+
+    #[test]
+    fn in_autopushes_pins() {
+        let mut a = Assembler::<32>::new();
+        a.r#in(InSource::PINS, 4);
+
+        let config = Config {
+            in_pin_base: 4,
+            autopush: true,
+            push_threshold: 8,
+            in_shift_direction: ShiftDirection::Left,
+            ..Config::new()
+        };
+
+        let mut sim = Simulator::new(&a.assemble_program(), config).unwrap();
+        sim.set_input(4, true);
+        sim.set_input(7, true);
+        sim.run(2);
+
+        assert_eq!(sim.read(), Some(0b1001_1001));
+    }
+
+    #[test]
+    fn wait_irq_stalls_until_set() {
+        let mut a = Assembler::<32>::new();
+        a.wait(1, WaitSource::IRQ, 2, false);
+        a.set(SetDestination::PINS, 1);
+
+        let config = Config {set_pins: (0, 1), output_pins: 1, ..Config::new()};
+        let mut sim = Simulator::new(&a.assemble_program(), config).unwrap();
+
+        sim.run(10);
+        assert_eq!(sim.pc(), 0);
+
+        sim.set_irq(2);
+        sim.run(2);
+        assert_eq!(sim.pins() & 1, 1);
+        assert_eq!(sim.irq_flags(), 0);
+    }
+
+    #[test]
+    fn irq_wait_stalls_until_cleared() {
+        let mut a = Assembler::<32>::new();
+        a.irq(false, true, 1, false);
+        a.set(SetDestination::PINS, 1);
+
+        let config = Config {set_pins: (0, 1), output_pins: 1, ..Config::new()};
+        let mut sim = Simulator::new(&a.assemble_program(), config).unwrap();
+
+        sim.run(10);
+        assert_eq!(sim.pc(), 0);
+        assert_eq!(sim.irq_flags(), 0b10);
+
+        sim.clear_irq(1);
+        sim.run(2);
+        assert_eq!(sim.pins() & 1, 1);
+        assert_eq!(sim.irq_flags(), 0);
+    }
+
+    #[test]
+    fn relative_irqs_are_offset_by_the_state_machine() {
+        let mut a = Assembler::<32>::new();
+        a.irq(false, false, 3, true);
+        a.irq(false, false, 6, true);
+
+        let config = Config {index: 2, ..Config::new()};
+        let mut sim = Simulator::new(&a.assemble_program(), config).unwrap();
+
+        // 3 + 2 wraps to 1, and 6 keeps its top bit to become 4
+        sim.run(2);
+        assert_eq!(sim.irq_flags(), 0b1_0010);
+    }
+
+    #[test]
+    fn out_exec_runs_the_word() {
+        let mut a = Assembler::<32>::new();
+        a.pull(false, true);
+        a.out(OutDestination::EXEC, 16);
+
+        let set_x = Instruction {
+            operands: InstructionOperands::SET {destination: SetDestination::X, data: 21},
+            delay: 0,
+            side_set: None,
+        }.encode(SideSet::default());
+
+        let mut sim = Simulator::new(&a.assemble_program(), Config::new()).unwrap();
+        sim.write(set_x as u32);
+        sim.run(3);
+
+        assert_eq!(sim.x(), 21);
+    }
+
+    #[test]
+    fn jumps_past_the_program_run_whatever_is_there() {
+        let mut a = Assembler::<32>::new();
+        a.set(SetDestination::X, 7);
+        let mut past_the_end = a.label_at_offset(20);
+        a.jmp(JmpCondition::Always, &mut past_the_end);
+
+        let mut sim = Simulator::new(&a.assemble_program(), Config::new()).unwrap();
+        sim.run(2);
+        assert_eq!(sim.pc(), 20);
+
+        // Empty memory reads as `jmp 0`
+        sim.run(1);
+        assert_eq!(sim.pc(), 0);
+    }
+
+    #[test]
+    fn programs_run_at_their_origin() {
+        let mut a = Assembler::<32>::new();
+        let mut top = a.label();
+        a.bind(&mut top);
+        a.set(SetDestination::X, 3);
+        a.jmp(JmpCondition::Always, &mut top);
+
+        let mut program = a.assemble_program();
+        program.origin = Some(30);
+
+        let mut sim = Simulator::new(&program, Config::new()).unwrap();
+        assert_eq!(sim.pc(), 30);
+        sim.run(2);
+        assert_eq!(sim.pc(), 30);
+        assert_eq!(sim.x(), 3);
+
+        program.origin = Some(31);
+        assert!(matches!(Simulator::new(&program, Config::new()), Err(Error::ProgramTooLong)));
+    }
+}
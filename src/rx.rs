@@ -1,85 +1,132 @@
-use rp2040_hal::pac::{PIO0, PIO1};
-use rp2040_hal::pio::{self, SM0, SM1, SM2, SM3};
-
-/// This entire thing exists to unfuck the rp2040_hal library design for Rx
-pub enum Rx {
-    PIO0SM0(pio::Rx<(PIO0, SM0)>),
-    PIO0SM1(pio::Rx<(PIO0, SM1)>),
-    PIO0SM2(pio::Rx<(PIO0, SM2)>),
-    PIO0SM3(pio::Rx<(PIO0, SM3)>),
-    PIO1SM0(pio::Rx<(PIO1, SM0)>),
-    PIO1SM1(pio::Rx<(PIO1, SM1)>),
-    PIO1SM2(pio::Rx<(PIO1, SM2)>),
-    PIO1SM3(pio::Rx<(PIO1, SM3)>),
+use crate::hal::pio::PioIRQ;
+
+use crate::fifo::{dispatch, fifo, Direction};
+use crate::hardware::Hardware;
+
+#[derive(Debug)]
+pub enum Error {
+    /// Hardware hasn't been initialized yet, so there's no timer
+    NoHardware,
+    /// The FIFO stayed empty for the whole timeout
+    Timeout,
 }
 
+fifo!(
+    /// The rx FIFO of any state machine on either block, so it can be stored
+    /// without naming its HAL type
+    Rx, Rx, Direction::Rx
+);
+
 impl Rx {
+    /// Get the FIFO address, for use as a DMA read address.
+    pub fn fifo_address(&self) -> *const u32 {
+        dispatch!(self, rx => rx.fifo_address())
+    }
+
+    /// Get the DREQ number that paces DMA transfers out of the FIFO.
+    pub fn dreq_value(&self) -> u8 {
+        dispatch!(self, rx => rx.dreq_value())
+    }
+
     /// Indicate if the rx FIFO is empty
     pub fn is_empty(&self) -> bool {
-        match self {
-            Rx::PIO0SM0(rx) => rx.is_empty(),
-            Rx::PIO0SM1(rx) => rx.is_empty(),
-            Rx::PIO0SM2(rx) => rx.is_empty(),
-            Rx::PIO0SM3(rx) => rx.is_empty(),
-            Rx::PIO1SM0(rx) => rx.is_empty(),
-            Rx::PIO1SM1(rx) => rx.is_empty(),
-            Rx::PIO1SM2(rx) => rx.is_empty(),
-            Rx::PIO1SM3(rx) => rx.is_empty(),
-        }
+        dispatch!(self, rx => rx.is_empty())
     }
 
-     /// Indicate if the rx FIFO is full
-     pub fn is_full(&self) -> bool {
-        match self {
-            Rx::PIO0SM0(rx) => rx.is_full(),
-            Rx::PIO0SM1(rx) => rx.is_full(),
-            Rx::PIO0SM2(rx) => rx.is_full(),
-            Rx::PIO0SM3(rx) => rx.is_full(),
-            Rx::PIO1SM0(rx) => rx.is_full(),
-            Rx::PIO1SM1(rx) => rx.is_full(),
-            Rx::PIO1SM2(rx) => rx.is_full(),
-            Rx::PIO1SM3(rx) => rx.is_full(),
-        }
+    /// Indicate if the rx FIFO is full
+    pub fn is_full(&self) -> bool {
+        dispatch!(self, rx => rx.is_full())
     }
 
     /// Get the next element from RX FIFO.
     ///
     /// Returns `None` if the FIFO is empty.
     pub fn read(&mut self) -> Option<u32> {
-        match self {
-            Rx::PIO0SM0(rx) => rx.read(),
-            Rx::PIO0SM1(rx) => rx.read(),
-            Rx::PIO0SM2(rx) => rx.read(),
-            Rx::PIO0SM3(rx) => rx.read(),
-            Rx::PIO1SM0(rx) => rx.read(),
-            Rx::PIO1SM1(rx) => rx.read(),
-            Rx::PIO1SM2(rx) => rx.read(),
-            Rx::PIO1SM3(rx) => rx.read(),
+        dispatch!(self, rx => rx.read())
+    }
+
+    /// Get the next element from RX FIFO, waiting for as long as it takes for
+    /// one to arrive.
+    pub fn read_blocking(&mut self) -> u32 {
+        loop {
+            if let Some(value) = self.read() {
+                return value
+            }
         }
     }
-}
 
-impl From<pio::Rx<(PIO0, SM0)>> for Rx {
-    fn from(value: pio::Rx<(PIO0, SM0)>) -> Self {Rx::PIO0SM0(value)}
-}
-impl From<pio::Rx<(PIO0, SM1)>> for Rx {
-    fn from(value: pio::Rx<(PIO0, SM1)>) -> Self {Rx::PIO0SM1(value)}
-}
-impl From<pio::Rx<(PIO0, SM2)>> for Rx {
-    fn from(value: pio::Rx<(PIO0, SM2)>) -> Self {Rx::PIO0SM2(value)}
-}
-impl From<pio::Rx<(PIO0, SM3)>> for Rx {
-    fn from(value: pio::Rx<(PIO0, SM3)>) -> Self {Rx::PIO0SM3(value)}
-}
-impl From<pio::Rx<(PIO1, SM0)>> for Rx {
-    fn from(value: pio::Rx<(PIO1, SM0)>) -> Self {Rx::PIO1SM0(value)}
-}
-impl From<pio::Rx<(PIO1, SM1)>> for Rx {
-    fn from(value: pio::Rx<(PIO1, SM1)>) -> Self {Rx::PIO1SM1(value)}
-}
-impl From<pio::Rx<(PIO1, SM2)>> for Rx {
-    fn from(value: pio::Rx<(PIO1, SM2)>) -> Self {Rx::PIO1SM2(value)}
+    /// Get the next element from RX FIFO, giving up if nothing arrives within
+    /// `timeout_us` microseconds.
+    pub fn read_timeout(&mut self, timeout_us: u32) -> Result<u32, Error> {
+        let timer = Hardware::get().ok_or(Error::NoHardware)?.timer();
+        let start = timer.get_counter_low();
+
+        loop {
+            if let Some(value) = self.read() {
+                return Ok(value)
+            }
+
+            if timer.get_counter_low().wrapping_sub(start) >= timeout_us {
+                return Err(Error::Timeout)
+            }
+        }
+    }
+
+    /// Turn autopush on or off while the state machine is running.
+    pub fn enable_autopush(&mut self, enable: bool) {
+        dispatch!(self, rx => rx.enable_autopush(enable))
+    }
+
+    /// Raise an interrupt on `id` while the rx FIFO isn't empty.
+    pub fn enable_rx_not_empty_interrupt(&self, id: PioIRQ) {
+        dispatch!(self, rx => rx.enable_rx_not_empty_interrupt(id))
+    }
+
+    pub fn disable_rx_not_empty_interrupt(&self, id: PioIRQ) {
+        dispatch!(self, rx => rx.disable_rx_not_empty_interrupt(id))
+    }
+
+    /// Force the rx not empty interrupt on `id` on or off.
+    pub fn force_rx_not_empty_interrupt(&self, id: PioIRQ, state: bool) {
+        dispatch!(self, rx => rx.force_rx_not_empty_interrupt(id, state))
+    }
 }
-impl From<pio::Rx<(PIO1, SM3)>> for Rx {
-    fn from(value: pio::Rx<(PIO1, SM3)>) -> Self {Rx::PIO1SM3(value)}
+
+#[cfg(all(test, feature = "host"))]
+mod tests {
+    use super::*;
+
+    use crate::mock;
+
+    fn rx() -> Rx {
+        mock::rx_tx().0
+    }
+
+    #[test]
+    fn read_timeout_gives_up_while_empty() {
+        Hardware::init(12_000_000);
+        let mut rx = rx();
+
+        let start = mock::timer::now_us();
+
+        assert!(matches!(rx.read_timeout(100), Err(Error::Timeout)));
+        assert!((100..=102).contains(&(mock::timer::now_us() - start)));
+
+        mock::pio::push_received(0, 0, 7);
+        assert_eq!(rx.read_timeout(100).unwrap(), 7);
+    }
+
+    #[test]
+    fn read_timeout_survives_the_counter_wrapping() {
+        Hardware::init(12_000_000);
+        let mut rx = rx();
+
+        // Start the wait just before the low 32 bits roll over
+        mock::timer::advance(u32::MAX as u64 - 10 - mock::timer::now_us());
+        let start = mock::timer::now_us();
+
+        assert!(matches!(rx.read_timeout(100), Err(Error::Timeout)));
+        assert!(mock::timer::now_us() > u32::MAX as u64);
+        assert!((100..=102).contains(&(mock::timer::now_us() - start)));
+    }
 }
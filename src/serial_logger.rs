@@ -124,3 +124,32 @@ impl Log for SerialLogger {
 
     fn flush(&self) {}
 }
+
+#[cfg(all(test, feature = "host"))]
+mod tests {
+    use super::*;
+
+    fn log(level: Level, args: Arguments) {
+        SerialLogger::new().log(&log::Record::builder().level(level).args(args).build());
+    }
+
+    #[test]
+    fn messages_are_colored_by_level() {
+        Hardware::init(12_000_000);
+
+        log(Level::Warn, format_args!("low battery: {}%", 5));
+        log(Level::Trace, format_args!("tick"));
+
+        let written = Hardware::get().unwrap().get_usb_mut().unwrap().take_written();
+        assert_eq!(written, "\x1b[33;1mlow battery: 5%\x1b[0m\r\n\x1b[36mtick\x1b[0m\r\n");
+    }
+
+    #[test]
+    fn nothing_is_written_without_usb() {
+        Hardware::init(12_000_000);
+        let mut usb = Hardware::get().unwrap().take_usb().unwrap();
+
+        log(Level::Error, format_args!("lost"));
+        assert_eq!(usb.take_written(), "");
+    }
+}
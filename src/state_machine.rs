@@ -1,6 +1,10 @@
 use core::cell::Cell;
 
-use rp2040_hal::pio::{self, InstalledProgram, PIOBuilder, PIOExt, Running, Rx, StateMachineIndex, Stopped, Tx, UninitStateMachine};
+use ::pio::Wrap;
+
+use crate::hal::pio::{self, Buffers, InstalledProgram, PIOBuilder, PIOExt, PinDir, Running, Rx, ShiftDirection, StateMachineIndex, Stopped, Tx, UninitStateMachine};
+
+use crate::clock_divisor::ClockDivisor;
 
 #[derive(Debug)]
 pub enum Error {
@@ -10,6 +14,67 @@ pub enum Error {
     NoProgramToUninstall,
 }
 
+/// How to set up a state machine
+///
+/// Pins left as `None` keep the hardware defaults. Start from `new` and fill
+/// in what the program needs, or use a ready made one like
+/// `ws2812b::config`.
+#[derive(Clone, Copy, Debug)]
+pub struct StateMachineConfig {
+    pub clock_divisor: ClockDivisor,
+    /// Base and count of the pins driven by `set`
+    pub set_pins: Option<(u8, u8)>,
+    /// Base and count of the pins driven by `out` and `mov pins`
+    pub out_pins: Option<(u8, u8)>,
+    /// First pin driven by side-set. The count comes from the program.
+    pub side_set_base: Option<u8>,
+    /// First pin read by `in` and `wait pin`
+    pub in_pin_base: Option<u8>,
+    /// Pin tested by `jmp pin`
+    pub jmp_pin: Option<u8>,
+    /// Base and count of the pins switched to outputs before starting
+    pub output_pins: Option<(u8, u8)>,
+    pub autopull: bool,
+    /// Number of bits shifted out before pulling the next word, from 1 to 32
+    pub pull_threshold: u8,
+    pub out_shift_direction: ShiftDirection,
+    pub autopush: bool,
+    /// Number of bits shifted in before pushing a word, from 1 to 32
+    pub push_threshold: u8,
+    pub in_shift_direction: ShiftDirection,
+    /// How the FIFOs are shared. `Buffers::OnlyTx` joins them into a single 8
+    /// deep tx FIFO.
+    pub buffers: Buffers,
+    /// Replaces the program's own wrap, relative to the start of the program
+    pub wrap: Option<Wrap>,
+}
+
+impl StateMachineConfig {
+    /// A config with no pins and the hardware's reset values for everything
+    /// else
+    pub const fn new(clock_divisor: ClockDivisor) -> StateMachineConfig {
+        StateMachineConfig {
+            clock_divisor,
+            set_pins: None,
+            out_pins: None,
+            side_set_base: None,
+            in_pin_base: None,
+            jmp_pin: None,
+            output_pins: None,
+            autopull: false,
+            pull_threshold: 32,
+            out_shift_direction: ShiftDirection::Right,
+            autopush: false,
+            push_threshold: 32,
+            in_shift_direction: ShiftDirection::Right,
+            buffers: Buffers::RxTx,
+            wrap: None,
+        }
+    }
+}
+
+/// The rx and tx of a programmed state machine
+pub type Channels<PIO, SM> = (Rx<(PIO, SM)>, Tx<(PIO, SM)>);
 
 enum StateMachineKind<PIO: PIOExt, SM: StateMachineIndex> {
     Running(pio::StateMachine<(PIO, SM), Running>),
@@ -42,32 +107,69 @@ impl<PIO: PIOExt, SM: StateMachineIndex> StateMachine<PIO, SM> {
     }
 
     /// Program this state machine
+    ///
+    /// The machine is left stopped, with every pin in `config.output_pins`
+    /// switched to an output.
     pub fn program(
         &mut self,
         installed: &InstalledProgram<PIO>,
-        pins: (u8, u8),
-        clock_divisor: (u16, u8)
-    ) -> Result<(Rx<(PIO, SM)>, Tx<(PIO, SM)>), Error> {
+        config: &StateMachineConfig,
+    ) -> Result<Channels<PIO, SM>, Error> {
         critical_section::with(|_| {
-            // Make sure the machine is uninitialized before trying to program it
-            let sm = self.sm.replace(StateMachineKind::BeingSwapped);
-            let StateMachineKind::Uninitialized(sm) = sm else {
-                return Err(Error::ProgrammingFailed)
+            // Make sure the machine is uninitialized before trying to program
+            // it, putting back whatever it was otherwise
+            let sm = match self.sm.replace(StateMachineKind::BeingSwapped) {
+                StateMachineKind::Uninitialized(sm) => sm,
+                sm => {
+                    self.sm.set(sm);
+                    return Err(Error::ProgrammingFailed)
+                },
             };
 
-            // Get values in order
-            let (base, count) = pins;
-            let (int, frac) = clock_divisor;
-
-            let program;
+            let mut program;
             unsafe {program = installed.share();}
 
+            if let Some(wrap) = config.wrap {
+                let Ok(wrapped) = program.set_wrap(wrap) else {
+                    self.sm.set(StateMachineKind::Uninitialized(sm));
+                    return Err(Error::ProgrammingFailed)
+                };
+                program = wrapped;
+            }
+
             // Program it
-            let (sm, rx, tx) = PIOBuilder
+            let mut builder = PIOBuilder
                 ::from_installed_program(program)
-                .set_pins(base, count)
-                .clock_divisor_fixed_point(int, frac)
-                .build(sm);
+                .autopull(config.autopull)
+                .pull_threshold(config.pull_threshold)
+                .out_shift_direction(config.out_shift_direction)
+                .autopush(config.autopush)
+                .push_threshold(config.push_threshold)
+                .in_shift_direction(config.in_shift_direction)
+                .buffers(config.buffers)
+                .clock_divisor_fixed_point(config.clock_divisor.int, config.clock_divisor.frac);
+
+            if let Some((base, count)) = config.set_pins {
+                builder = builder.set_pins(base, count);
+            }
+            if let Some((base, count)) = config.out_pins {
+                builder = builder.out_pins(base, count);
+            }
+            if let Some(base) = config.side_set_base {
+                builder = builder.side_set_pin_base(base);
+            }
+            if let Some(base) = config.in_pin_base {
+                builder = builder.in_pin_base(base);
+            }
+            if let Some(pin) = config.jmp_pin {
+                builder = builder.jmp_pin(pin);
+            }
+
+            let (mut sm, rx, tx) = builder.build(sm);
+
+            if let Some((base, count)) = config.output_pins {
+                sm.set_pindirs((base..base + count).map(|pin| (pin, PinDir::Output)));
+            }
 
             // Change values
             self.sm = Cell::new(StateMachineKind::Stopped(sm));
@@ -80,12 +182,20 @@ impl<PIO: PIOExt, SM: StateMachineIndex> StateMachine<PIO, SM> {
         critical_section::with(|_| {
             let sm = self.sm.replace(StateMachineKind::BeingSwapped);
             // Stop the machine if it's still running
-            if let StateMachineKind::Running(_) = &sm {
-                self.stop()?;
-            }
+            let sm = match sm {
+                StateMachineKind::Running(sm) => {
+                    self.running = false;
+                    StateMachineKind::Stopped(sm.stop())
+                },
+                sm => sm,
+            };
 
-            let StateMachineKind::Stopped(sm) = sm else {
-                return Err(Error::NoProgramToUninstall)
+            let sm = match sm {
+                StateMachineKind::Stopped(sm) => sm,
+                sm => {
+                    self.sm.set(sm);
+                    return Err(Error::NoProgramToUninstall)
+                },
             };
 
             let (sm, _) = sm.uninit(rx, tx);
@@ -99,9 +209,12 @@ impl<PIO: PIOExt, SM: StateMachineIndex> StateMachine<PIO, SM> {
 
     pub fn start(&mut self) -> Result<(), Error> {
         critical_section::with(|_| {
-            let sm = self.sm.replace(StateMachineKind::BeingSwapped);
-            let StateMachineKind::Stopped(sm) = sm else {
-                return Err(Error::FailedToStart);
+            let sm = match self.sm.replace(StateMachineKind::BeingSwapped) {
+                StateMachineKind::Stopped(sm) => sm,
+                sm => {
+                    self.sm.set(sm);
+                    return Err(Error::FailedToStart)
+                },
             };
 
             let sm = sm.start();
@@ -113,9 +226,12 @@ impl<PIO: PIOExt, SM: StateMachineIndex> StateMachine<PIO, SM> {
 
     pub fn stop(&mut self) -> Result<(), Error> {
         critical_section::with(|_| {
-            let sm = self.sm.replace(StateMachineKind::BeingSwapped);
-            let StateMachineKind::Running(sm) = sm else {
-                return Err(Error::FailedToStop)
+            let sm = match self.sm.replace(StateMachineKind::BeingSwapped) {
+                StateMachineKind::Running(sm) => sm,
+                sm => {
+                    self.sm.set(sm);
+                    return Err(Error::FailedToStop)
+                },
             };
 
             let sm = sm.stop();
@@ -125,3 +241,94 @@ impl<PIO: PIOExt, SM: StateMachineIndex> StateMachine<PIO, SM> {
         })
     }
 }
+
+#[cfg(all(test, feature = "host"))]
+mod tests {
+    use super::*;
+
+    use crate::hal::pac::{PIO0, RESETS};
+    use crate::hal::pio::SM1;
+    use crate::mock;
+    use crate::ws2812b;
+
+    const CONFIG: StateMachineConfig = StateMachineConfig {
+        output_pins: Some((5, 2)),
+        pull_threshold: 24,
+        ..StateMachineConfig::new(ClockDivisor::new(2, 128))
+    };
+
+    fn state_machine() -> (StateMachine<PIO0, SM1>, InstalledProgram<PIO0>) {
+        let (mut pio, _, sm1, _, _) = PIO0.split(&mut RESETS);
+        let installed = pio.install(&ws2812b::program()).unwrap();
+
+        (StateMachine::new(sm1), installed)
+    }
+
+    #[test]
+    fn program_builds_with_the_config() {
+        let (mut sm, installed) = state_machine();
+        let (_rx, _tx) = sm.program(&installed, &CONFIG).unwrap();
+
+        assert!(sm.is_initialized());
+        assert!(!sm.is_running());
+
+        let record = mock::pio::record(0, 1);
+        let config = record.config.unwrap();
+        assert_eq!(config.offset, installed.offset());
+        assert_eq!(config.pull_threshold, 24);
+        assert_eq!(config.clock_divisor, (2, 128));
+        assert_eq!(record.pindirs, 0b11 << 5);
+    }
+
+    #[test]
+    fn programming_twice_leaves_it_as_it_was() {
+        let (mut sm, installed) = state_machine();
+        let (rx, tx) = sm.program(&installed, &CONFIG).unwrap();
+        sm.start().unwrap();
+
+        assert!(matches!(sm.program(&installed, &CONFIG), Err(Error::ProgrammingFailed)));
+        assert!(matches!(sm.start(), Err(Error::FailedToStart)));
+        assert!(sm.is_initialized());
+        assert!(sm.is_running());
+        assert_eq!(mock::pio::enabled_mask(0), 0b0010);
+
+        // Still stops and uninstalls like nothing happened
+        sm.stop().unwrap();
+        assert_eq!(mock::pio::enabled_mask(0), 0);
+        sm.uninstall(rx, tx).unwrap();
+        assert!(!sm.is_initialized());
+    }
+
+    #[test]
+    fn a_bad_wrap_leaves_it_unprogrammed() {
+        let (mut sm, installed) = state_machine();
+        let config = StateMachineConfig {wrap: Some(Wrap {source: 31, target: 0}), ..CONFIG};
+
+        assert!(matches!(sm.program(&installed, &config), Err(Error::ProgrammingFailed)));
+        assert!(!sm.is_initialized());
+        assert!(mock::pio::record(0, 1).config.is_none());
+
+        sm.program(&installed, &CONFIG).unwrap();
+    }
+
+    #[test]
+    fn uninstall_stops_it_first() {
+        let (mut sm, installed) = state_machine();
+        let (rx, tx) = sm.program(&installed, &CONFIG).unwrap();
+
+        sm.start().unwrap();
+        assert!(sm.is_running());
+        assert_eq!(mock::pio::enabled_mask(0), 0b0010);
+
+        sm.uninstall(rx, tx).unwrap();
+
+        assert!(!sm.is_initialized());
+        assert!(!sm.is_running());
+        assert_eq!(mock::pio::enabled_mask(0), 0);
+        assert!(mock::pio::record(0, 1).config.is_none());
+
+        // It can be programmed again afterwards
+        sm.program(&installed, &CONFIG).unwrap();
+        assert!(matches!(sm.stop(), Err(Error::FailedToStop)));
+    }
+}
@@ -0,0 +1,411 @@
+//! High level driver for a strip of WS2812B style LEDs
+//!
+//! A strip claims a state machine and a GPIO pin, then takes care of packing
+//! colors, keeping the FIFO fed, and latching each frame. When a DMA channel is
+//! free, frames are streamed out in the background.
+//!
+//! Frames are double buffered. `write` always packs into the back buffer while
+//! the front buffer is being sent, and `present` swaps the two once the strip
+//! has latched the last frame. This keeps animations from tearing.
+
+use alloc::vec::Vec;
+
+use crate::chipset::Chipset;
+use crate::color::{ColorOrder, Rgb8, Rgbw16, Rgbw8};
+use crate::correction::{Correction, Gamma};
+use crate::dither::{self, Dither};
+use crate::hal::Timer;
+use crate::hardware::{self, Hardware};
+use crate::output::{self, Output};
+use crate::pio;
+use crate::state_machine;
+
+#[derive(Debug)]
+pub enum Error {
+    /// Hardware hasn't been initialized yet
+    NoHardware,
+    /// The pin has already been taken, or doesn't exist
+    PinUnavailable,
+    /// The chipset's timing can't be hit with the current system clock
+    UnsupportedTiming,
+    /// Failed to install the program. If neither block could take the strip,
+    /// this is why the last one tried couldn't.
+    Pio(pio::Error),
+    /// Failed to start, stop, or uninstall the state machine
+    StateMachine(state_machine::Error),
+    /// Failed to give something back to the hardware
+    Hardware(hardware::Error),
+}
+
+impl From<pio::Error> for Error {
+    fn from(value: pio::Error) -> Self {Error::Pio(value)}
+}
+
+impl From<state_machine::Error> for Error {
+    fn from(value: state_machine::Error) -> Self {Error::StateMachine(value)}
+}
+
+impl From<hardware::Error> for Error {
+    fn from(value: hardware::Error) -> Self {Error::Hardware(value)}
+}
+
+impl From<output::Error> for Error {
+    fn from(value: output::Error) -> Self {
+        match value {
+            output::Error::PinUnavailable => Error::PinUnavailable,
+            output::Error::Pio(error) => Error::Pio(error),
+            output::Error::StateMachine(error) => Error::StateMachine(error),
+            output::Error::Hardware(error) => Error::Hardware(error),
+        }
+    }
+}
+
+/// A strip of LEDs on a single pin
+pub struct Strip {
+    /// Only `None` once the strip has been freed
+    output: Option<Output>,
+    color_order: ColorOrder,
+    reset_us: u32,
+    correction: Correction,
+    dither: Option<Dither>,
+    /// The last written frame after correction, before it's cut to 8 bits
+    pixels: Vec<Rgbw16>,
+    front: Vec<u32>,
+    back: Vec<u32>,
+    /// Whether the back buffer has been packed since the last present
+    back_ready: bool,
+    latch_pending: bool,
+    timer: Timer,
+}
+
+impl Strip {
+    /// Sets up a strip on a pin
+    ///
+    /// This takes the pin from the hardware, claims a free state machine from
+    /// either PIO block, installs the output program with the chipset's timing
+    /// to it, and starts it. A DMA channel is claimed too if there is one
+    /// left, otherwise frames are fed to the FIFO by the CPU.
+    ///
+    /// * `pin` - GPIO number of the data line
+    /// * `chipset` - Which LEDs are on the strip
+    /// * `color_order` - The order the strip expects its channels in. Orders
+    ///   with a white channel switch the state machine to 32 bit pixels.
+    pub fn new(pin: u8, chipset: Chipset, color_order: ColorOrder) -> Result<Strip, Error> {
+        let hardware = Hardware::get().ok_or(Error::NoHardware)?;
+
+        let timing = chipset.timing();
+        let program_timing = timing
+            .program_timing(hardware.system_clock_hz())
+            .ok_or(Error::UnsupportedTiming)?;
+
+        let output = Output::claim(hardware, pin, &program_timing, color_order.bits_per_pixel())?;
+
+        // The state machine holds the line low until the first frame
+        if let Err(error) = output.start(hardware) {
+            let _ = output.free(hardware);
+            return Err(error.into())
+        }
+
+        Ok(Strip {
+            output: Some(output),
+            color_order,
+            reset_us: timing.reset_us,
+            correction: Correction::default(),
+            dither: None,
+            pixels: Vec::new(),
+            front: Vec::new(),
+            back: Vec::new(),
+            back_ready: false,
+            latch_pending: false,
+            timer: hardware.timer(),
+        })
+    }
+
+    /// Stops the strip
+    ///
+    /// The state machine, DMA channel, and pin are all given back to the
+    /// hardware for something else to use. Dropping the strip does the same,
+    /// but without a way to see if it worked.
+    pub fn free(mut self) -> Result<(), Error> {
+        self.release()
+    }
+
+    /// Sets the gamma curve applied when frames are packed
+    pub fn set_gamma(&mut self, gamma: Gamma) {
+        self.correction.gamma = gamma;
+    }
+
+    /// Sets the global brightness applied when frames are packed
+    pub fn set_brightness(&mut self, brightness: u8) {
+        self.correction.brightness = brightness;
+    }
+
+    pub fn brightness(&self) -> u8 {
+        self.correction.brightness
+    }
+
+    /// Turns temporal dithering on or off
+    ///
+    /// Dithering smooths out dim fades, but only while frames keep being
+    /// presented, so call `present` continuously when it's on.
+    pub fn set_dithering(&mut self, enabled: bool) {
+        self.dither = enabled.then(Dither::new);
+    }
+
+    /// Packs a frame of pixels into the back buffer, ready for `present`
+    ///
+    /// Gamma and brightness are applied on the way in, so `pixels` is left as
+    /// it is. This never touches the frame that is currently being sent, so
+    /// it's safe to call while the strip is busy.
+    ///
+    /// On RGBW strips, white is extracted from each color automatically.
+    pub fn write(&mut self, pixels: &[Rgb8]) {
+        let correction = self.correction;
+        let has_white = self.color_order.channels() == 4;
+
+        self.pixels.clear();
+        self.pixels.extend(pixels.iter().map(|&pixel| {
+            let pixel = match has_white {
+                true => Rgbw8::extract_white(pixel),
+                false => pixel.into(),
+            };
+
+            correction.apply_rgbw(pixel)
+        }));
+
+        self.pack();
+    }
+
+    /// Packs a frame of pixels with their own white channel into the back
+    /// buffer, ready for `present`
+    ///
+    /// The white channel is dropped on strips that don't have one.
+    pub fn write_rgbw(&mut self, pixels: &[Rgbw8]) {
+        let correction = self.correction;
+
+        self.pixels.clear();
+        self.pixels.extend(pixels.iter().map(|&pixel| correction.apply_rgbw(pixel)));
+
+        self.pack();
+    }
+
+    /// Swaps the back buffer to the front and sends it to the strip
+    ///
+    /// The swap only happens after the previous frame has been clocked out and
+    /// latched, so a frame is never changed part way through. Presenting again
+    /// without a new `write` resends the last frame, with fresh dithering.
+    ///
+    /// With DMA this returns as soon as the transfer starts, leaving the CPU
+    /// free to render the next frame. Without it, this blocks until every word
+    /// is in the FIFO.
+    pub fn present(&mut self) {
+        if !self.back_ready {
+            self.pack();
+        }
+
+        self.wait_for_latch();
+        core::mem::swap(&mut self.front, &mut self.back);
+
+        let Output {tx, dma, ..} = self.output.as_mut().expect("a strip has an output until it's freed");
+
+        match dma {
+            Some(dma) => unsafe {
+                // The front buffer is only touched again after the next latch,
+                // and dropping the strip stops the transfer before freeing it
+                dma.start(&self.front, tx);
+            },
+            None => {
+                for &word in &self.front {
+                    tx.write_blocking(word);
+                }
+            }
+        }
+
+        self.back_ready = false;
+        self.latch_pending = true;
+    }
+
+    /// Returns whether or not a frame is still being clocked out
+    pub fn is_busy(&self) -> bool {
+        self.output().is_busy()
+    }
+
+    /// Sets a function to call from the DMA interrupt whenever a frame has
+    /// been handed to the FIFO
+    ///
+    /// Returns `false` if this strip doesn't have a DMA channel.
+    pub fn set_callback(&mut self, callback: Option<fn()>) -> bool {
+        let Some(dma) = self.output.as_mut().and_then(|output| output.dma.as_mut()) else {
+            return false
+        };
+
+        dma.set_callback(callback);
+        true
+    }
+
+    /// Cuts the last written frame down to 8 bits and packs it into the back
+    /// buffer
+    fn pack(&mut self) {
+        let color_order = self.color_order;
+
+        self.back.clear();
+
+        match &mut self.dither {
+            Some(dither) => self.back.extend(self.pixels.iter().enumerate().map(|(index, &pixel)| {
+                color_order.pack(dither.quantize(index, pixel))
+            })),
+            None => self.back.extend(self.pixels.iter().map(|&pixel| {
+                color_order.pack(dither::round(pixel))
+            })),
+        }
+
+        self.back_ready = true;
+    }
+
+    /// Waits for the last frame to be clocked out and latched
+    fn wait_for_latch(&mut self) {
+        if !self.latch_pending {
+            return
+        }
+
+        self.output().wait_until_sent();
+        output::latch(&self.timer, self.reset_us);
+
+        self.latch_pending = false;
+    }
+
+    fn output(&self) -> &Output {
+        self.output.as_ref().expect("a strip has an output until it's freed")
+    }
+
+    /// Stops the DMA and gives the output back, if that hasn't happened yet
+    fn release(&mut self) -> Result<(), Error> {
+        let Some(mut output) = self.output.take() else {
+            return Ok(())
+        };
+
+        // Stop reading the front buffer first, even if nothing else can be
+        // given back
+        if let Some(dma) = &mut output.dma {
+            dma.abort();
+        }
+
+        let hardware = Hardware::get().ok_or(Error::NoHardware)?;
+        output.free(hardware)?;
+
+        Ok(())
+    }
+}
+
+impl Drop for Strip {
+    /// Makes sure the DMA is done with the front buffer before it's freed
+    fn drop(&mut self) {
+        let _ = self.release();
+    }
+}
+
+#[cfg(all(test, feature = "host"))]
+mod tests {
+    use super::*;
+
+    use crate::dma;
+    use crate::mock;
+
+    #[test]
+    fn present_sends_the_packed_frame() {
+        Hardware::init(12_000_000);
+
+        let mut strip = Strip::new(2, Chipset::Ws2812b, ColorOrder::Grb).unwrap();
+        strip.set_gamma(Gamma::Linear);
+        strip.write(&[Rgb8 {r: 255, g: 0, b: 0}, Rgb8 {r: 0, g: 255, b: 255}]);
+        strip.present();
+
+        assert_eq!(mock::pio::take_sent(0, 0), [0x00ff0000, 0xff00ff00]);
+
+        let config = mock::pio::record(0, 0).config.unwrap();
+        assert_eq!(config.pull_threshold, 24);
+        assert_eq!(config.side_set_base, 2);
+        assert_eq!(mock::pio::enabled_mask(0), 0b0001);
+    }
+
+    #[test]
+    fn present_waits_for_the_last_frame_to_clock_out() {
+        Hardware::init(12_000_000);
+
+        let mut strip = Strip::new(2, Chipset::Ws2812b, ColorOrder::Grb).unwrap();
+        strip.write(&[Rgb8::default(); 2]);
+        strip.present();
+
+        let record = mock::pio::record(0, 0);
+        assert_eq!(record.tx_level, 2);
+        assert!(!record.tx_stalled);
+
+        // Only stalls once the first frame has been pulled
+        strip.present();
+        assert!(mock::pio::record(0, 0).tx_stalled);
+    }
+
+    #[test]
+    fn latches_without_the_delay() {
+        Hardware::init(12_000_000);
+
+        let mut strip = Strip::new(2, Chipset::Ws2812b, ColorOrder::Grb).unwrap();
+        let _delay = Hardware::get().unwrap().take_delay().unwrap();
+
+        strip.present();
+        let start = mock::timer::now_us();
+        strip.present();
+
+        assert!(mock::timer::now_us() - start >= Chipset::Ws2812b.timing().reset_us as u64);
+    }
+
+    #[test]
+    fn free_gives_everything_back() {
+        Hardware::init(12_000_000);
+
+        let strip = Strip::new(2, Chipset::Ws2812b, ColorOrder::Grbw).unwrap();
+        assert!(Hardware::get().unwrap().take_pin(2).is_none());
+        assert_eq!(mock::pio::record(0, 0).config.unwrap().pull_threshold, 32);
+
+        strip.free().unwrap();
+
+        let pin = Hardware::get().unwrap().take_pin(2).unwrap();
+        assert_eq!(pin.function(), mock::gpio::DynFunction::Null);
+        assert_eq!(mock::pio::enabled_mask(0), 0);
+        assert_eq!(Hardware::get().unwrap().get_pio0_mut().unwrap().free_instructions(), 32);
+        assert!(mock::pio::record(0, 0).config.is_none());
+    }
+
+    #[test]
+    fn dropping_gives_everything_back() {
+        Hardware::init(12_000_000);
+
+        let mut strip = Strip::new(2, Chipset::Ws2812b, ColorOrder::Grb).unwrap();
+        strip.write(&[Rgb8::new(1, 2, 3)]);
+        strip.present();
+        drop(strip);
+
+        let hardware = Hardware::get().unwrap();
+        assert!(hardware.take_pin(2).is_some());
+        assert_eq!(core::iter::from_fn(|| hardware.take_dma_channel()).count(), dma::NUM_CHANNELS);
+
+        let pio0 = hardware.get_pio0_mut().unwrap();
+        assert_eq!(pio0.claimed_mask(), 0);
+        assert_eq!(pio0.free_instructions(), 32);
+    }
+
+    #[test]
+    fn new_reports_why_the_last_block_failed() {
+        Hardware::init(12_000_000);
+
+        let hardware = Hardware::get().unwrap();
+        let _pio0 = hardware.take_pio0().unwrap();
+        let _pio1 = hardware.take_pio1().unwrap();
+
+        let result = Strip::new(2, Chipset::Ws2812b, ColorOrder::Grb);
+        assert!(matches!(result, Err(Error::Pio(pio::Error::MissingPIO))));
+
+        // The pin goes back when nothing could take the strip
+        assert!(hardware.take_pin(2).is_some());
+    }
+}
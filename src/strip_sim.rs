@@ -0,0 +1,375 @@
+//! Runs effects against a pretend strip, for working on them without a board
+//!
+//! `StripSim` steps an `Effect` through time and keeps every frame it draws.
+//! Frames can be written out as PNGs, one block of color per LED, or played
+//! back in a true color terminal. `Frame::check_golden` compares a frame with
+//! a PNG written earlier, for tests that pin down exactly what an effect
+//! looks like.
+//!
+//! Golden images are only ever written with `UPDATE_GOLDEN` set in the
+//! environment, to create them or to rewrite them all after an effect changes
+//! on purpose. A missing one is an error otherwise. The images the tests
+//! check against are kept in `tests/golden`.
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt::Write as _;
+use core::time::Duration;
+
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use crate::color::Rgb8;
+use crate::effect::{Effect, Layout};
+
+/// Frame rate used unless `with_fps` says otherwise
+const DEFAULT_FPS: u32 = 60;
+
+/// Environment variable that makes `check_golden` write its image
+const UPDATE_GOLDEN: &str = "UPDATE_GOLDEN";
+
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    Encoding(png::EncodingError),
+    Decoding(png::DecodingError),
+    /// The golden image isn't 8 bit RGB, so it wasn't written by `write_png`
+    UnsupportedImage,
+    /// The golden image isn't a whole number of LEDs in the frame's layout
+    SizeMismatch {width: u32, height: u32},
+    /// The golden image doesn't exist, set `UPDATE_GOLDEN` to write it
+    MissingGolden,
+    /// An LED doesn't match the golden image
+    Mismatch {x: usize, y: usize, expected: Rgb8, found: Rgb8},
+}
+
+impl From<io::Error> for Error {
+    fn from(value: io::Error) -> Self {Error::Io(value)}
+}
+
+impl From<png::EncodingError> for Error {
+    fn from(value: png::EncodingError) -> Self {Error::Encoding(value)}
+}
+
+impl From<png::DecodingError> for Error {
+    fn from(value: png::DecodingError) -> Self {Error::Decoding(value)}
+}
+
+/// A single frame drawn by an effect
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Frame {
+    /// When the frame was drawn, in microseconds from the start of the run
+    pub time_us: u64,
+    pub layout: Layout,
+    /// One color per LED, in wiring order
+    pub pixels: Vec<Rgb8>,
+}
+
+impl Frame {
+    /// The color of the LED at a position, or `None` if it's off the edge
+    pub fn get(&self, x: usize, y: usize) -> Option<Rgb8> {
+        self.layout.index(x, y).map(|index| self.pixels[index])
+    }
+
+    /// Draws the frame with ANSI true color escapes, two columns per LED
+    ///
+    /// Every row of the layout is a line, ending in a reset.
+    pub fn to_ansi(&self) -> String {
+        let (width, height) = self.layout.size();
+        let mut out = String::new();
+
+        for y in 0..height {
+            for x in 0..width {
+                let Rgb8 {r, g, b} = self.get(x, y).unwrap_or_default();
+                let _ = write!(out, "\x1b[48;2;{r};{g};{b}m  ");
+            }
+
+            out.push_str("\x1b[0m\n");
+        }
+
+        out
+    }
+
+    /// Encodes the frame as an 8 bit RGB PNG
+    ///
+    /// Each LED is drawn as a `scale` by `scale` square.
+    pub fn to_png(&self, scale: u32) -> Result<Vec<u8>, Error> {
+        let (width, height) = self.layout.size();
+        let (width, height) = (width as u32 * scale, height as u32 * scale);
+
+        let mut data = Vec::with_capacity((width * height * 3) as usize);
+
+        for y in 0..height {
+            for x in 0..width {
+                let Rgb8 {r, g, b} = self
+                    .get((x / scale) as usize, (y / scale) as usize)
+                    .unwrap_or_default();
+
+                data.extend_from_slice(&[r, g, b]);
+            }
+        }
+
+        let mut png = Vec::new();
+        let mut encoder = png::Encoder::new(&mut png, width, height);
+        encoder.set_color(png::ColorType::Rgb);
+        encoder.set_depth(png::BitDepth::Eight);
+
+        let mut writer = encoder.write_header()?;
+        writer.write_image_data(&data)?;
+        writer.finish()?;
+
+        Ok(png)
+    }
+
+    /// Writes the frame to a PNG file, see `to_png`
+    pub fn write_png(&self, path: impl AsRef<Path>, scale: u32) -> Result<(), Error> {
+        fs::write(path, self.to_png(scale)?)?;
+        Ok(())
+    }
+
+    /// Compares the frame with a golden PNG, LED by LED
+    ///
+    /// The image can be any scale, as long as it's a whole number of pixels
+    /// per LED. If `UPDATE_GOLDEN` is set, the frame is written to it at
+    /// `scale` instead.
+    pub fn check_golden(&self, path: impl AsRef<Path>, scale: u32) -> Result<(), Error> {
+        let path = path.as_ref();
+
+        if std::env::var_os(UPDATE_GOLDEN).is_some() {
+            return self.write_png(path, scale)
+        }
+
+        if !path.exists() {
+            return Err(Error::MissingGolden)
+        }
+
+        let decoder = png::Decoder::new(fs::File::open(path)?);
+        let mut reader = decoder.read_info()?;
+        let mut data = alloc::vec![0; reader.output_buffer_size()];
+        let info = reader.next_frame(&mut data)?;
+
+        if info.color_type != png::ColorType::Rgb || info.bit_depth != png::BitDepth::Eight {
+            return Err(Error::UnsupportedImage)
+        }
+
+        let (width, height) = self.layout.size();
+        let scale = info.width as usize / width;
+
+        if scale == 0 || info.width as usize != width * scale || info.height as usize != height * scale {
+            return Err(Error::SizeMismatch {width: info.width, height: info.height})
+        }
+
+        for y in 0..height {
+            for x in 0..width {
+                let offset = (y * scale * info.line_size) + (x * scale * 3);
+                let expected = Rgb8::new(data[offset], data[offset + 1], data[offset + 2]);
+                let found = self.get(x, y).unwrap_or_default();
+
+                if expected != found {
+                    return Err(Error::Mismatch {x, y, expected, found})
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// A strip or matrix that only exists in memory
+pub struct StripSim {
+    layout: Layout,
+    frame_interval_us: u64,
+    time_us: u64,
+    /// What the effect drew last, handed back to it for the next frame
+    pixels: Vec<Rgb8>,
+    frames: Vec<Frame>,
+}
+
+impl StripSim {
+    /// A dark strip at 60 frames per second
+    pub fn new(layout: Layout) -> StripSim {
+        StripSim {
+            layout,
+            frame_interval_us: 1_000_000 / DEFAULT_FPS as u64,
+            time_us: 0,
+            pixels: alloc::vec![Rgb8::default(); layout.len()],
+            frames: Vec::new(),
+        }
+    }
+
+    /// Sets how often frames are drawn
+    pub fn with_fps(mut self, fps: u32) -> StripSim {
+        self.frame_interval_us = 1_000_000 / fps.max(1) as u64;
+        self
+    }
+
+    pub fn layout(&self) -> Layout {
+        self.layout
+    }
+
+    /// Draws a number of frames, carrying on from where the last run left off
+    ///
+    /// Returns just the frames drawn by this run.
+    pub fn run(&mut self, effect: &mut impl Effect, frames: usize) -> &[Frame] {
+        let start = self.frames.len();
+
+        for _ in 0..frames {
+            effect.render(self.time_us, &self.layout, &mut self.pixels);
+
+            self.frames.push(Frame {time_us: self.time_us, layout: self.layout, pixels: self.pixels.clone()});
+            self.time_us += self.frame_interval_us;
+        }
+
+        &self.frames[start..]
+    }
+
+    /// Every frame drawn so far
+    pub fn frames(&self) -> &[Frame] {
+        &self.frames
+    }
+
+    /// Writes every frame to `dir` as `frame_0000.png`, `frame_0001.png`, and
+    /// so on
+    ///
+    /// The directory is created if it's missing.
+    pub fn write_png_sequence(&self, dir: impl AsRef<Path>, scale: u32) -> Result<(), Error> {
+        let dir = dir.as_ref();
+        fs::create_dir_all(dir)?;
+
+        for (index, frame) in self.frames.iter().enumerate() {
+            frame.write_png(dir.join(std::format!("frame_{index:04}.png")), scale)?;
+        }
+
+        Ok(())
+    }
+
+    /// Plays every frame in a true color terminal
+    ///
+    /// Each frame is drawn over the last one. With `realtime` set, this waits
+    /// between frames so the effect plays at its real speed.
+    pub fn play(&self, out: &mut impl io::Write, realtime: bool) -> io::Result<()> {
+        let (_, height) = self.layout.size();
+
+        for (index, frame) in self.frames.iter().enumerate() {
+            if index > 0 {
+                write!(out, "\x1b[{height}A")?;
+            }
+
+            out.write_all(frame.to_ansi().as_bytes())?;
+            out.flush()?;
+
+            if realtime {
+                std::thread::sleep(Duration::from_micros(self.frame_interval_us));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Lights one LED at a time, moving along once per frame
+    fn chase(time_us: u64, layout: &Layout, pixels: &mut [Rgb8]) {
+        let lit = (time_us / 100_000) as usize % layout.len();
+
+        for (index, pixel) in pixels.iter_mut().enumerate() {
+            *pixel = match index == lit {
+                true => Rgb8::new(255, 0, 0),
+                false => Rgb8::default(),
+            };
+        }
+    }
+
+    /// A golden image kept with the crate
+    fn fixture(name: &str) -> std::path::PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/golden").join(name)
+    }
+
+    /// Somewhere to write images that aren't kept
+    fn golden_path(name: &str) -> std::path::PathBuf {
+        let dir = std::env::temp_dir().join(std::format!("strip_sim_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        dir.join(name)
+    }
+
+    #[test]
+    fn run_steps_time_by_the_frame_rate() {
+        let mut sim = StripSim::new(Layout::strip(4).unwrap()).with_fps(10);
+        let frames = sim.run(&mut chase, 5);
+
+        let lit: Vec<usize> = frames
+            .iter()
+            .map(|frame| frame.pixels.iter().position(|&pixel| pixel != Rgb8::default()).unwrap())
+            .collect();
+
+        assert_eq!(lit, [0, 1, 2, 3, 0]);
+        assert_eq!(sim.frames()[4].time_us, 400_000);
+    }
+
+    #[test]
+    fn ansi_follows_the_serpentine() {
+        let layout = Layout::matrix(2, 2, true).unwrap();
+        let mut sim = StripSim::new(layout);
+
+        // Wiring order 2 is the right hand end of the second row
+        sim.run(&mut |_: u64, _: &Layout, pixels: &mut [Rgb8]| pixels[2] = Rgb8::new(1, 2, 3), 1);
+
+        let off = "\x1b[48;2;0;0;0m  ";
+        let on = "\x1b[48;2;1;2;3m  ";
+        let expected = std::format!("{off}{off}\x1b[0m\n{off}{on}\x1b[0m\n");
+
+        assert_eq!(sim.frames()[0].to_ansi(), expected);
+    }
+
+    #[test]
+    fn golden_images_round_trip() {
+        let layout = Layout::matrix(3, 2, false).unwrap();
+        let mut sim = StripSim::new(layout).with_fps(10);
+        sim.run(&mut chase, 2);
+
+        // Read back at a different scale to the one it was written at
+        let path = golden_path("chase.png");
+        sim.frames()[0].write_png(&path, 4).unwrap();
+        sim.frames()[0].check_golden(&path, 1).unwrap();
+
+        let Err(Error::Mismatch {x, y, expected, found}) = sim.frames()[1].check_golden(&path, 4) else {
+            panic!("the second frame should differ");
+        };
+
+        assert_eq!((x, y), (0, 0));
+        assert_eq!(expected, Rgb8::new(255, 0, 0));
+        assert_eq!(found, Rgb8::default());
+    }
+
+    #[test]
+    fn missing_golden_images_are_not_written() {
+        // Writing them is exactly what the variable asks for
+        if std::env::var_os(UPDATE_GOLDEN).is_some() {
+            return
+        }
+
+        let mut sim = StripSim::new(Layout::strip(2).unwrap());
+        sim.run(&mut chase, 1);
+
+        let path = golden_path("missing.png");
+        let _ = fs::remove_file(&path);
+
+        assert!(matches!(sim.frames()[0].check_golden(&path, 1), Err(Error::MissingGolden)));
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn chase_matches_its_golden_images() {
+        let layout = Layout::matrix(3, 2, true).unwrap();
+        let mut sim = StripSim::new(layout).with_fps(10);
+        sim.run(&mut chase, 4);
+
+        for (index, frame) in sim.frames().iter().enumerate() {
+            frame.check_golden(fixture(&std::format!("chase_{index}.png")), 8).unwrap();
+        }
+    }
+}
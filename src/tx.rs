@@ -1,31 +1,26 @@
-use rp2040_hal::pac::{PIO0, PIO1};
-use rp2040_hal::pio::{self, SM0, SM1, SM2, SM3};
-
-/// This entire thing exists to unfuck the rp2040_hal library design for Tx
-pub enum Tx {
-    PIO0SM0(pio::Tx<(PIO0, SM0)>),
-    PIO0SM1(pio::Tx<(PIO0, SM1)>),
-    PIO0SM2(pio::Tx<(PIO0, SM2)>),
-    PIO0SM3(pio::Tx<(PIO0, SM3)>),
-    PIO1SM0(pio::Tx<(PIO1, SM0)>),
-    PIO1SM1(pio::Tx<(PIO1, SM1)>),
-    PIO1SM2(pio::Tx<(PIO1, SM2)>),
-    PIO1SM3(pio::Tx<(PIO1, SM3)>),
+use crate::hal::pio::PioIRQ;
+
+use crate::fifo::{dispatch, fifo, Direction};
+use crate::hardware::Hardware;
+
+#[derive(Debug)]
+pub enum Error {
+    /// Hardware hasn't been initialized yet, so there's no timer
+    NoHardware,
+    /// The FIFO stayed full for the whole timeout
+    Timeout,
 }
 
+fifo!(
+    /// The tx FIFO of any state machine on either block, so it can be stored
+    /// without naming its HAL type
+    Tx, Tx, Direction::Tx
+);
+
 impl Tx {
     /// Clears the `tx_stalled` flag.
     pub fn clear_stalled_flag(&self) {
-        match self {
-            Tx::PIO0SM0(tx) => tx.clear_stalled_flag(),
-            Tx::PIO0SM1(tx) => tx.clear_stalled_flag(),
-            Tx::PIO0SM2(tx) => tx.clear_stalled_flag(),
-            Tx::PIO0SM3(tx) => tx.clear_stalled_flag(),
-            Tx::PIO1SM0(tx) => tx.clear_stalled_flag(),
-            Tx::PIO1SM1(tx) => tx.clear_stalled_flag(),
-            Tx::PIO1SM2(tx) => tx.clear_stalled_flag(),
-            Tx::PIO1SM3(tx) => tx.clear_stalled_flag(),
-        }
+        dispatch!(self, tx => tx.clear_stalled_flag())
     }
 
     /// Checks if the state machine has stalled on empty TX FIFO during a blocking PULL, or an OUT
@@ -33,84 +28,125 @@ impl Tx {
     ///
     /// **Note this is a sticky flag and may not reflect the current state of the machine.**
     pub fn has_stalled(&self) -> bool {
-        match self {
-            Tx::PIO0SM0(tx) => tx.has_stalled(),
-            Tx::PIO0SM1(tx) => tx.has_stalled(),
-            Tx::PIO0SM2(tx) => tx.has_stalled(),
-            Tx::PIO0SM3(tx) => tx.has_stalled(),
-            Tx::PIO1SM0(tx) => tx.has_stalled(),
-            Tx::PIO1SM1(tx) => tx.has_stalled(),
-            Tx::PIO1SM2(tx) => tx.has_stalled(),
-            Tx::PIO1SM3(tx) => tx.has_stalled(),
-        }
+        dispatch!(self, tx => tx.has_stalled())
+    }
+
+    /// Get the FIFO address, for use as a DMA write address.
+    pub fn fifo_address(&self) -> *const u32 {
+        dispatch!(self, tx => tx.fifo_address())
+    }
+
+    /// Get the DREQ number that paces DMA transfers into the FIFO.
+    pub fn dreq_value(&self) -> u8 {
+        dispatch!(self, tx => tx.dreq_value())
     }
 
     /// Indicate if the tx FIFO is empty
     pub fn is_empty(&self) -> bool {
-        match self {
-            Tx::PIO0SM0(tx) => tx.is_empty(),
-            Tx::PIO0SM1(tx) => tx.is_empty(),
-            Tx::PIO0SM2(tx) => tx.is_empty(),
-            Tx::PIO0SM3(tx) => tx.is_empty(),
-            Tx::PIO1SM0(tx) => tx.is_empty(),
-            Tx::PIO1SM1(tx) => tx.is_empty(),
-            Tx::PIO1SM2(tx) => tx.is_empty(),
-            Tx::PIO1SM3(tx) => tx.is_empty(),
-        }
+        dispatch!(self, tx => tx.is_empty())
     }
 
-     /// Indicate if the tx FIFO is full
-     pub fn is_full(&self) -> bool {
-        match self {
-            Tx::PIO0SM0(tx) => tx.is_full(),
-            Tx::PIO0SM1(tx) => tx.is_full(),
-            Tx::PIO0SM2(tx) => tx.is_full(),
-            Tx::PIO0SM3(tx) => tx.is_full(),
-            Tx::PIO1SM0(tx) => tx.is_full(),
-            Tx::PIO1SM1(tx) => tx.is_full(),
-            Tx::PIO1SM2(tx) => tx.is_full(),
-            Tx::PIO1SM3(tx) => tx.is_full(),
-        }
+    /// Indicate if the tx FIFO is full
+    pub fn is_full(&self) -> bool {
+        dispatch!(self, tx => tx.is_full())
     }
 
     /// Write a u32 value to TX FIFO.
     ///
     /// Returns `true` if the value was written to FIFO, `false` otherwise.
     pub fn write(&mut self, value: u32) -> bool {
-        match self {
-            Tx::PIO0SM0(tx) => tx.write(value),
-            Tx::PIO0SM1(tx) => tx.write(value),
-            Tx::PIO0SM2(tx) => tx.write(value),
-            Tx::PIO0SM3(tx) => tx.write(value),
-            Tx::PIO1SM0(tx) => tx.write(value),
-            Tx::PIO1SM1(tx) => tx.write(value),
-            Tx::PIO1SM2(tx) => tx.write(value),
-            Tx::PIO1SM3(tx) => tx.write(value),
+        dispatch!(self, tx => tx.write(value))
+    }
+
+    /// Write a u32 value to TX FIFO, waiting for as long as it takes for there
+    /// to be room.
+    pub fn write_blocking(&mut self, value: u32) {
+        while !self.write(value) {}
+    }
+
+    /// Write a u32 value to TX FIFO, giving up if there's still no room after
+    /// `timeout_us` microseconds.
+    pub fn write_timeout(&mut self, value: u32, timeout_us: u32) -> Result<(), Error> {
+        let timer = Hardware::get().ok_or(Error::NoHardware)?.timer();
+        let start = timer.get_counter_low();
+
+        while !self.write(value) {
+            if timer.get_counter_low().wrapping_sub(start) >= timeout_us {
+                return Err(Error::Timeout)
+            }
         }
+
+        Ok(())
     }
-}
 
-impl From<pio::Tx<(PIO0, SM0)>> for Tx {
-    fn from(value: pio::Tx<(PIO0, SM0)>) -> Self {Tx::PIO0SM0(value)}
-}
-impl From<pio::Tx<(PIO0, SM1)>> for Tx {
-    fn from(value: pio::Tx<(PIO0, SM1)>) -> Self {Tx::PIO0SM1(value)}
-}
-impl From<pio::Tx<(PIO0, SM2)>> for Tx {
-    fn from(value: pio::Tx<(PIO0, SM2)>) -> Self {Tx::PIO0SM2(value)}
-}
-impl From<pio::Tx<(PIO0, SM3)>> for Tx {
-    fn from(value: pio::Tx<(PIO0, SM3)>) -> Self {Tx::PIO0SM3(value)}
-}
-impl From<pio::Tx<(PIO1, SM0)>> for Tx {
-    fn from(value: pio::Tx<(PIO1, SM0)>) -> Self {Tx::PIO1SM0(value)}
-}
-impl From<pio::Tx<(PIO1, SM1)>> for Tx {
-    fn from(value: pio::Tx<(PIO1, SM1)>) -> Self {Tx::PIO1SM1(value)}
-}
-impl From<pio::Tx<(PIO1, SM2)>> for Tx {
-    fn from(value: pio::Tx<(PIO1, SM2)>) -> Self {Tx::PIO1SM2(value)}
+    /// Write a u8 value to TX FIFO, replicated into all 4 bytes of the word.
+    ///
+    /// Returns `true` if the value was written to FIFO, `false` otherwise.
+    pub fn write_u8_replicated(&mut self, value: u8) -> bool {
+        dispatch!(self, tx => tx.write_u8_replicated(value))
+    }
+
+    /// Write a u16 value to TX FIFO, replicated into both halves of the word.
+    ///
+    /// Returns `true` if the value was written to FIFO, `false` otherwise.
+    pub fn write_u16_replicated(&mut self, value: u16) -> bool {
+        dispatch!(self, tx => tx.write_u16_replicated(value))
+    }
+
+    /// Raise an interrupt on `id` while the tx FIFO isn't full.
+    pub fn enable_tx_not_full_interrupt(&self, id: PioIRQ) {
+        dispatch!(self, tx => tx.enable_tx_not_full_interrupt(id))
+    }
+
+    pub fn disable_tx_not_full_interrupt(&self, id: PioIRQ) {
+        dispatch!(self, tx => tx.disable_tx_not_full_interrupt(id))
+    }
+
+    /// Force the tx not full interrupt on `id`.
+    pub fn force_tx_not_full_interrupt(&self, id: PioIRQ) {
+        dispatch!(self, tx => tx.force_tx_not_full_interrupt(id))
+    }
 }
-impl From<pio::Tx<(PIO1, SM3)>> for Tx {
-    fn from(value: pio::Tx<(PIO1, SM3)>) -> Self {Tx::PIO1SM3(value)}
+
+#[cfg(all(test, feature = "host"))]
+mod tests {
+    use super::*;
+
+    use crate::mock;
+
+    fn tx() -> Tx {
+        mock::rx_tx().1
+    }
+
+    #[test]
+    fn write_timeout_gives_up_while_full() {
+        Hardware::init(12_000_000);
+        let mut tx = tx();
+
+        mock::pio::set_tx_full(0, 0, true);
+        let start = mock::timer::now_us();
+
+        assert!(matches!(tx.write_timeout(1, 100), Err(Error::Timeout)));
+        assert!((100..=102).contains(&(mock::timer::now_us() - start)));
+
+        mock::pio::set_tx_full(0, 0, false);
+        tx.write_timeout(2, 100).unwrap();
+
+        assert_eq!(mock::pio::take_sent(0, 0), [2]);
+    }
+
+    #[test]
+    fn write_timeout_survives_the_counter_wrapping() {
+        Hardware::init(12_000_000);
+        let mut tx = tx();
+
+        // Start the wait just before the low 32 bits roll over
+        mock::timer::advance(u32::MAX as u64 - 10 - mock::timer::now_us());
+        mock::pio::set_tx_full(0, 0, true);
+        let start = mock::timer::now_us();
+
+        assert!(matches!(tx.write_timeout(1, 100), Err(Error::Timeout)));
+        assert!(mock::timer::now_us() > u32::MAX as u64);
+        assert!((100..=102).contains(&(mock::timer::now_us() - start)));
+    }
 }
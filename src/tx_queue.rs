@@ -0,0 +1,383 @@
+//! Feeds a tx FIFO from a ring buffer in the PIO interrupt
+//!
+//! Words are pushed into the queue from the main loop, and the PIO's first
+//! interrupt line tops the FIFO back up whenever it isn't full. That keeps a
+//! state machine fed without DMA and without the CPU spinning on `is_full`.
+//!
+//! The interrupt is only enabled while there are words waiting, so an idle
+//! queue costs nothing. The library leaves the interrupt vectors to the
+//! application, whose `PIO0_IRQ_0` and `PIO1_IRQ_0` handlers have to call
+//! `drain_block`.
+
+use alloc::vec::Vec;
+#[cfg(feature = "host")]
+use core::cell::UnsafeCell;
+#[cfg(not(feature = "host"))]
+use core::ptr::addr_of_mut;
+
+use crate::hal::pac;
+use crate::hal::pio::PioIRQ;
+
+use crate::tx::Tx;
+
+/// One slot per state machine, 4 for each block
+const NUM_SLOTS: usize = 8;
+
+/// The queues being drained by the interrupts, indexed by `slot`
+#[cfg(not(feature = "host"))]
+static mut QUEUES: [Option<Queue>; NUM_SLOTS] = [const { None }; NUM_SLOTS];
+
+// Each thread has its own mock hardware on the host, so it gets its own queues
+#[cfg(feature = "host")]
+std::thread_local! {
+    static QUEUES: UnsafeCell<[Option<Queue>; NUM_SLOTS]> = const { UnsafeCell::new([const { None }; NUM_SLOTS]) };
+}
+
+#[derive(Debug)]
+pub enum Error {
+    /// The state machine already has a queue
+    AlreadyQueued,
+    /// A queue needs room for at least one word
+    ZeroCapacity,
+}
+
+/// A fixed size ring buffer of words
+struct Ring {
+    words: Vec<u32>,
+    /// Index of the oldest word
+    read: usize,
+    len: usize,
+}
+
+impl Ring {
+    fn new(capacity: usize) -> Ring {
+        Ring {words: alloc::vec![0; capacity], read: 0, len: 0}
+    }
+
+    fn capacity(&self) -> usize {
+        self.words.len()
+    }
+
+    fn push(&mut self, word: u32) -> bool {
+        if self.len == self.capacity() {
+            return false
+        }
+
+        let write = (self.read + self.len) % self.capacity();
+        self.words[write] = word;
+        self.len += 1;
+
+        true
+    }
+
+    fn peek(&self) -> Option<u32> {
+        (self.len > 0).then(|| self.words[self.read])
+    }
+
+    fn pop(&mut self) {
+        self.read = (self.read + 1) % self.capacity();
+        self.len -= 1;
+    }
+
+    fn clear(&mut self) {
+        self.read = 0;
+        self.len = 0;
+    }
+}
+
+/// The tx and words shared with the interrupt
+struct Queue {
+    tx: Tx,
+    ring: Ring,
+}
+
+impl Queue {
+    /// Moves words into the FIFO until it's full or the ring runs dry
+    ///
+    /// The interrupt is turned off once there's nothing left, since it would
+    /// otherwise keep firing for as long as the FIFO has room.
+    fn drain(&mut self) {
+        while let Some(word) = self.ring.peek() {
+            if !self.tx.write(word) {
+                return
+            }
+
+            self.ring.pop();
+        }
+
+        self.tx.disable_tx_not_full_interrupt(PioIRQ::Irq0);
+    }
+}
+
+/// The producer side of a tx FIFO fed from an interrupt
+///
+/// The tx is held by the interrupt until `free` gives it back. Dropping the
+/// queue frees its slot too, but the tx is dropped along with it.
+pub struct TxQueue {
+    slot: usize,
+}
+
+impl TxQueue {
+    /// Hands a tx over to the PIO interrupt, with room to queue up `capacity`
+    /// words on top of what fits in the FIFO
+    pub fn new(tx: Tx, capacity: usize) -> Result<TxQueue, (Tx, Error)> {
+        if capacity == 0 {
+            return Err((tx, Error::ZeroCapacity))
+        }
+
+        let slot = slot(&tx);
+
+        critical_section::with(|_| {
+            let queue = unsafe { &mut queues()[slot] };
+
+            if queue.is_some() {
+                return Err((tx, Error::AlreadyQueued))
+            }
+
+            *queue = Some(Queue {tx, ring: Ring::new(capacity)});
+
+            unsafe {
+                pac::NVIC::unmask(match slot / 4 {
+                    0 => pac::Interrupt::PIO0_IRQ_0,
+                    _ => pac::Interrupt::PIO1_IRQ_0,
+                });
+            }
+
+            Ok(TxQueue {slot})
+        })
+    }
+
+    /// Stops feeding the FIFO and gives the tx back
+    ///
+    /// Anything still queued is dropped, but words already in the FIFO are
+    /// left to be clocked out.
+    pub fn free(mut self) -> Tx {
+        self.take().expect("a TxQueue always has a queue in its slot")
+    }
+
+    /// Queues a single word
+    ///
+    /// Returns `false` if the queue is full.
+    pub fn push(&mut self, word: u32) -> bool {
+        self.with(|queue| {
+            let pushed = queue.ring.push(word);
+            queue.tx.enable_tx_not_full_interrupt(PioIRQ::Irq0);
+            pushed
+        })
+    }
+
+    /// Queues as many words as there's room for
+    ///
+    /// Returns how many were queued. Pass the rest in again later.
+    pub fn push_slice(&mut self, words: &[u32]) -> usize {
+        self.with(|queue| {
+            let pushed = words.iter().take_while(|&&word| queue.ring.push(word)).count();
+            queue.tx.enable_tx_not_full_interrupt(PioIRQ::Irq0);
+            pushed
+        })
+    }
+
+    /// Queues every word, waiting for room whenever the queue fills up
+    pub fn push_all(&mut self, mut words: &[u32]) {
+        while !words.is_empty() {
+            let pushed = self.push_slice(words);
+            words = &words[pushed..];
+        }
+    }
+
+    /// Drops every word that hasn't made it into the FIFO yet
+    pub fn clear(&mut self) {
+        self.with(|queue| queue.ring.clear());
+    }
+
+    /// Number of words waiting to go into the FIFO
+    pub fn len(&self) -> usize {
+        self.with(|queue| queue.ring.len)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Most words that can be waiting at once
+    pub fn capacity(&self) -> usize {
+        self.with(|queue| queue.ring.capacity())
+    }
+
+    /// Returns whether or not every queued word has been clocked out of the
+    /// FIFO too
+    pub fn is_drained(&self) -> bool {
+        self.with(|queue| queue.ring.len == 0 && queue.tx.is_empty())
+    }
+
+    /// Stops the interrupt feeding the FIFO and empties the slot, if that
+    /// hasn't happened yet
+    fn take(&mut self) -> Option<Tx> {
+        critical_section::with(|_| {
+            let queue = unsafe { queues()[self.slot].take() }?;
+
+            queue.tx.disable_tx_not_full_interrupt(PioIRQ::Irq0);
+            Some(queue.tx)
+        })
+    }
+
+    fn with<T>(&self, f: impl FnOnce(&mut Queue) -> T) -> T {
+        critical_section::with(|_| {
+            let queue = unsafe { queues()[self.slot].as_mut() };
+            f(queue.expect("a TxQueue always has a queue in its slot"))
+        })
+    }
+}
+
+/// The queues being drained by the interrupts
+///
+/// # Safety
+/// Nothing else can be touching the queues while the reference is held.
+#[cfg(not(feature = "host"))]
+unsafe fn queues() -> &'static mut [Option<Queue>; NUM_SLOTS] {
+    &mut *addr_of_mut!(QUEUES)
+}
+
+/// This thread's queues
+///
+/// # Safety
+/// The reference can't be held across another call.
+#[cfg(feature = "host")]
+unsafe fn queues() -> &'static mut [Option<Queue>; NUM_SLOTS] {
+    QUEUES.with(|queues| &mut *queues.get())
+}
+
+impl Drop for TxQueue {
+    fn drop(&mut self) {
+        self.take();
+    }
+}
+
+/// Index into `QUEUES` for a tx
+fn slot(tx: &Tx) -> usize {
+    tx.block() as usize * 4 + tx.index() as usize
+}
+
+/// Tops up the FIFOs of a block's queues
+///
+/// This is what the application's `PIO0_IRQ_0` and `PIO1_IRQ_0` handlers
+/// have to run, for block 0 and 1. There are no interrupts on the host, so it
+/// has to be called by hand there.
+///
+/// # Safety
+/// Nothing else can be touching the queues at the same time. On the board,
+/// that means only calling it from the block's interrupt.
+pub unsafe fn drain_block(block: usize) {
+    for queue in queues()[block * 4..block * 4 + 4].iter_mut().flatten() {
+        queue.drain();
+    }
+}
+
+#[cfg(all(test, feature = "host"))]
+mod tests {
+    use super::*;
+
+    use crate::hardware::Hardware;
+    use crate::mock;
+
+    /// A queue feeding PIO0 SM0
+    fn queue(capacity: usize) -> TxQueue {
+        Hardware::init(12_000_000);
+        let (_, tx) = mock::rx_tx();
+
+        TxQueue::new(tx, capacity).map_err(|(_, error)| error).unwrap()
+    }
+
+    fn drain() {
+        unsafe { drain_block(0) }
+    }
+
+    #[test]
+    fn draining_stops_when_the_fifo_fills() {
+        let mut queue = queue(4);
+
+        assert_eq!(queue.push_slice(&[1, 2, 3]), 3);
+        assert!(mock::pio::take_sent(0, 0).is_empty());
+
+        mock::pio::set_tx_room(0, 0, Some(2));
+        drain();
+
+        assert_eq!(mock::pio::take_sent(0, 0), [1, 2]);
+        assert_eq!(queue.len(), 1);
+        assert!(!queue.is_drained());
+
+        mock::pio::set_tx_room(0, 0, None);
+        drain();
+
+        assert_eq!(mock::pio::take_sent(0, 0), [3]);
+
+        // The state machine pulls a word each time the FIFO is checked, and
+        // all three are still in it
+        let checks = core::iter::repeat_with(|| queue.is_drained()).take_while(|&drained| !drained).count();
+        assert_eq!(checks, 3);
+    }
+
+    #[test]
+    fn words_wrap_around_the_ring() {
+        let mut queue = queue(3);
+
+        assert_eq!(queue.push_slice(&[1, 2, 3]), 3);
+        mock::pio::set_tx_room(0, 0, Some(2));
+        drain();
+
+        assert!(queue.push(4));
+        assert!(queue.push(5));
+        assert!(!queue.push(6));
+
+        mock::pio::set_tx_room(0, 0, None);
+        drain();
+
+        assert_eq!(mock::pio::take_sent(0, 0), [1, 2, 3, 4, 5]);
+    }
+
+    #[test]
+    fn a_full_queue_takes_nothing_more() {
+        let mut queue = queue(2);
+        mock::pio::set_tx_full(0, 0, true);
+
+        assert_eq!(queue.push_slice(&[1, 2, 3]), 2);
+        assert!(!queue.push(4));
+        assert_eq!(queue.len(), queue.capacity());
+
+        queue.clear();
+        assert!(queue.is_empty());
+        assert!(queue.push(5));
+    }
+
+    #[test]
+    fn dropping_frees_the_slot() {
+        let mut queue = queue(2);
+        queue.push(1);
+        drop(queue);
+
+        assert!(unsafe { queues()[0].is_none() });
+
+        drain();
+        assert!(mock::pio::take_sent(0, 0).is_empty());
+    }
+
+    #[test]
+    fn free_gives_the_slot_back() {
+        let mut queue = queue(2);
+        queue.push(1);
+
+        let tx = queue.free();
+        drain();
+        assert!(mock::pio::take_sent(0, 0).is_empty());
+
+        let Err((tx, Error::ZeroCapacity)) = TxQueue::new(tx, 0) else {
+            panic!("a queue needs room for a word");
+        };
+
+        let mut queue = TxQueue::new(tx, 2).map_err(|(_, error)| error).unwrap();
+        queue.push(2);
+        drain();
+
+        assert_eq!(mock::pio::take_sent(0, 0), [2]);
+    }
+}
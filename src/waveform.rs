@@ -0,0 +1,364 @@
+//! Decodes a data line's waveform back into the pixels it carries
+//!
+//! This is the other end of `pio_sim`. Given the level of the data pin after
+//! every state machine cycle, it measures each high and low pulse, checks them
+//! against a chipset's `Timing`, and rebuilds the words that were written to
+//! the FIFO. That way a test can push colors through the real program and
+//! check exactly what a strip would see.
+
+use alloc::vec::Vec;
+
+use crate::chipset::Timing;
+
+/// How far pulses may stray from the chipset's timing
+///
+/// The defaults are the WS2812B datasheet's, which most clones share.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Tolerance {
+    /// Allowed error in a high pulse, in nanoseconds
+    pub high_ns: u32,
+    /// Allowed error in a whole bit, high and low together, in nanoseconds
+    pub period_ns: u32,
+}
+
+impl Default for Tolerance {
+    fn default() -> Self {
+        Tolerance {high_ns: 150, period_ns: 600}
+    }
+}
+
+/// A single bit as seen on the line
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Bit {
+    pub value: bool,
+    /// When the rising edge happened, in picoseconds from the start of the
+    /// trace
+    pub start_ps: u64,
+    pub high_ns: u32,
+    /// How long the line was low before the next bit, or `None` for the last
+    /// bit of a frame
+    pub low_ns: Option<u32>,
+}
+
+/// Something about the waveform a strip might not accept
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Violation {
+    /// A high pulse is too far from both the 0 and the 1 width. `bit` counts
+    /// from the start of the frame.
+    HighTime {frame: usize, bit: usize, high_ns: u32},
+    /// A bit's high and low together are too far from the bit period
+    Period {frame: usize, bit: usize, period_ns: u32},
+    /// A frame ended part way through a pixel
+    PartialPixel {frame: usize, bits: usize},
+    /// The trace ended before the line was low long enough to latch
+    NoLatch {frame: usize},
+}
+
+/// Everything sent between two latches
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Frame {
+    pub bits: Vec<Bit>,
+    /// The bits packed back into words, MSB first, laid out the same way as
+    /// the words written to the FIFO
+    pub pixels: Vec<u32>,
+    /// Whether or not the line was held low long enough afterwards for the
+    /// strip to show it
+    pub latched: bool,
+}
+
+/// A decoded trace
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Waveform {
+    pub frames: Vec<Frame>,
+    pub violations: Vec<Violation>,
+}
+
+impl Waveform {
+    /// Returns whether or not every pulse was within tolerance and every frame
+    /// was whole and latched
+    pub fn is_in_spec(&self) -> bool {
+        self.violations.is_empty()
+    }
+
+    /// The pixels of every frame, one after another
+    pub fn pixels(&self) -> impl Iterator<Item = u32> + '_ {
+        self.frames.iter().flat_map(|frame| frame.pixels.iter().copied())
+    }
+}
+
+/// Turns pin traces into frames for a particular chipset
+#[derive(Clone, Copy, Debug)]
+pub struct Decoder {
+    timing: Timing,
+    /// Length of one trace sample, in picoseconds
+    cycle_ps: u64,
+    bits_per_pixel: u8,
+    tolerance: Tolerance,
+}
+
+impl Decoder {
+    /// * `timing` - What the strip expects
+    /// * `cycle_ps` - How long each sample of the trace lasts. For a simulated
+    ///   state machine, that's `ClockDivisor::period_ps`.
+    /// * `bits_per_pixel` - 24 for RGB or 32 for RGBW
+    pub fn new(timing: Timing, cycle_ps: u64, bits_per_pixel: u8) -> Decoder {
+        Decoder {timing, cycle_ps, bits_per_pixel, tolerance: Tolerance::default()}
+    }
+
+    pub fn with_tolerance(self, tolerance: Tolerance) -> Decoder {
+        Decoder {tolerance, ..self}
+    }
+
+    /// Decodes the level of the data line after every cycle
+    ///
+    /// The line is assumed to have been low and latched before the trace
+    /// starts. A low stretch at least as long as the chipset's reset time
+    /// latches the frame, anything shorter is just the gap between bits.
+    pub fn decode(&self, trace: impl IntoIterator<Item = bool>) -> Waveform {
+        let mut waveform = Waveform::default();
+        let mut frame = Frame::default();
+
+        for (level, start, length) in runs(trace) {
+            let start_ps = start * self.cycle_ps;
+            let length_ps = length * self.cycle_ps;
+
+            match level {
+                true => frame.bits.push(Bit {
+                    value: self.is_one(length_ps),
+                    start_ps,
+                    high_ns: ns(length_ps),
+                    low_ns: None,
+                }),
+                false if length_ps >= self.timing.reset_us as u64 * 1_000_000 => {
+                    // Idle time before the first bit doesn't make a frame
+                    if !frame.bits.is_empty() {
+                        frame.latched = true;
+                        self.finish(&mut waveform, frame);
+                        frame = Frame::default();
+                    }
+                },
+                false => {
+                    // Low time before the first bit isn't part of any bit
+                    if let Some(bit) = frame.bits.last_mut() {
+                        bit.low_ns = Some(ns(length_ps));
+                    }
+                },
+            }
+        }
+
+        if !frame.bits.is_empty() {
+            // Whatever low time was left over wasn't long enough to latch
+            if let Some(bit) = frame.bits.last_mut() {
+                bit.low_ns = None;
+            }
+
+            self.finish(&mut waveform, frame);
+        }
+
+        waveform
+    }
+
+    /// Checks a frame's timing, packs its pixels, and adds it to the waveform
+    fn finish(&self, waveform: &mut Waveform, mut frame: Frame) {
+        let index = waveform.frames.len();
+        let period_ns = self.timing.period_ns();
+
+        for (bit_index, bit) in frame.bits.iter().enumerate() {
+            let expected = match bit.value {
+                true => self.timing.t1h_ns,
+                false => self.timing.t0h_ns,
+            };
+
+            if bit.high_ns.abs_diff(expected) > self.tolerance.high_ns {
+                waveform.violations.push(Violation::HighTime {frame: index, bit: bit_index, high_ns: bit.high_ns});
+            }
+
+            if let Some(low_ns) = bit.low_ns {
+                let bit_period_ns = bit.high_ns + low_ns;
+
+                if bit_period_ns.abs_diff(period_ns) > self.tolerance.period_ns {
+                    waveform.violations.push(Violation::Period {frame: index, bit: bit_index, period_ns: bit_period_ns});
+                }
+            }
+        }
+
+        let bits_per_pixel = self.bits_per_pixel as usize;
+        let partial = frame.bits.len() % bits_per_pixel;
+
+        if partial != 0 {
+            waveform.violations.push(Violation::PartialPixel {frame: index, bits: partial});
+        }
+
+        if !frame.latched {
+            waveform.violations.push(Violation::NoLatch {frame: index});
+        }
+
+        frame.pixels = frame.bits
+            .chunks_exact(bits_per_pixel)
+            .map(|pixel| {
+                let word = pixel.iter().fold(0u32, |word, bit| word << 1 | bit.value as u32);
+                word << (32 - bits_per_pixel)
+            })
+            .collect();
+
+        waveform.frames.push(frame);
+    }
+
+    /// Sorts a high pulse into a 0 or a 1, whichever width it's closer to
+    fn is_one(&self, high_ps: u64) -> bool {
+        let threshold_ps = (self.timing.t0h_ns as u64 + self.timing.t1h_ns as u64) * 1000 / 2;
+
+        high_ps > threshold_ps
+    }
+}
+
+/// Splits a trace into runs of the same level, as `(level, start, length)` in
+/// samples
+fn runs(trace: impl IntoIterator<Item = bool>) -> Vec<(bool, u64, u64)> {
+    let mut runs: Vec<(bool, u64, u64)> = Vec::new();
+
+    for (index, level) in trace.into_iter().enumerate() {
+        match runs.last_mut() {
+            Some((last, _, length)) if *last == level => *length += 1,
+            _ => runs.push((level, index as u64, 1)),
+        }
+    }
+
+    runs
+}
+
+/// Rounds picoseconds to the nearest nanosecond
+fn ns(ps: u64) -> u32 {
+    ((ps + 500) / 1000) as u32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::chipset::Chipset;
+    use crate::pio_sim::{Config, ShiftDirection, Simulator};
+    use crate::ws2812b;
+
+    const SYSTEM_CLOCK_HZ: u32 = 125_000_000;
+
+    /// Runs the strip program for a chipset over some words, then holds the
+    /// line long enough to latch
+    fn simulate(chipset: Chipset, bits_per_pixel: u8, words: &[u32]) -> Waveform {
+        let timing = chipset.timing();
+        let program_timing = timing.program_timing(SYSTEM_CLOCK_HZ).unwrap();
+
+        let config = Config {
+            output_pins: 1,
+            autopull: true,
+            pull_threshold: bits_per_pixel,
+            out_shift_direction: ShiftDirection::Left,
+            tx_depth: 8,
+            ..Config::new()
+        };
+
+        let mut sim = Simulator::new(&ws2812b::program_with_timing(&program_timing), config).unwrap();
+
+        for &word in words {
+            while !sim.write(word) {
+                sim.step();
+            }
+        }
+
+        assert!(sim.run_until_stalled(1_000_000));
+
+        let cycle_ps = program_timing.clock_divisor().period_ps(SYSTEM_CLOCK_HZ);
+        sim.run(timing.reset_us as u64 * 1_000_000 / cycle_ps + 1);
+
+        Decoder::new(timing, cycle_ps, bits_per_pixel).decode(sim.pin_trace(0))
+    }
+
+    #[test]
+    fn every_chipset_round_trips_in_spec() {
+        let words = [0xff_00_00_00, 0x00_ff_00_00, 0x00_00_ff_00, 0xa5_5a_c3_00, 0x01_80_7e_00];
+
+        for chipset in [
+            Chipset::Ws2811Slow,
+            Chipset::Ws2811,
+            Chipset::Ws2812b,
+            Chipset::Ws2813,
+            Chipset::Ws2815,
+            Chipset::Sk6812,
+            Chipset::Tm1814,
+            Chipset::Apa106,
+            Chipset::Ucs1903,
+        ] {
+            let waveform = simulate(chipset, 24, &words);
+
+            assert_eq!(waveform.violations, [], "{chipset:?}");
+            assert_eq!(waveform.frames.len(), 1, "{chipset:?}");
+            assert_eq!(waveform.pixels().collect::<Vec<_>>(), words, "{chipset:?}");
+        }
+    }
+
+    #[test]
+    fn rgbw_uses_all_32_bits() {
+        let words = [0x12_34_56_78, 0xff_00_ff_00];
+        let waveform = simulate(Chipset::Sk6812, 32, &words);
+
+        assert!(waveform.is_in_spec());
+        assert_eq!(waveform.pixels().collect::<Vec<_>>(), words);
+    }
+
+    #[test]
+    fn flags_pulses_out_of_spec() {
+        let timing = Chipset::Ws2812b.timing();
+
+        // 100ns samples, a 1 bit that's high for 1.2us then a 0 bit
+        let mut trace = Vec::new();
+        trace.extend([true; 12]);
+        trace.extend([false; 1]);
+        trace.extend([true; 4]);
+        trace.extend([false; 3000]);
+
+        let waveform = Decoder::new(timing, 100_000, 24).decode(trace);
+
+        assert_eq!(waveform.frames[0].bits.len(), 2);
+        assert!(waveform.frames[0].latched);
+        assert_eq!(waveform.violations, [
+            Violation::HighTime {frame: 0, bit: 0, high_ns: 1200},
+            Violation::PartialPixel {frame: 0, bits: 2},
+        ]);
+    }
+
+    #[test]
+    fn splits_frames_on_reset() {
+        let timing = Chipset::Ws2812b.timing();
+
+        let mut trace = Vec::new();
+        for _ in 0..2 {
+            for _ in 0..24 {
+                trace.extend([true; 4]);
+                trace.extend([false; 9]);
+            }
+            trace.extend([false; 3000]);
+        }
+
+        let waveform = Decoder::new(timing, 100_000, 24).decode(trace);
+
+        assert!(waveform.is_in_spec());
+        assert_eq!(waveform.frames.len(), 2);
+        assert_eq!(waveform.pixels().collect::<Vec<_>>(), [0, 0]);
+    }
+
+    #[test]
+    fn missing_latch_is_flagged() {
+        let timing = Chipset::Ws2812b.timing();
+
+        let mut trace = Vec::new();
+        for _ in 0..24 {
+            trace.extend([true; 8]);
+            trace.extend([false; 5]);
+        }
+
+        let waveform = Decoder::new(timing, 100_000, 24).decode(trace);
+
+        assert_eq!(waveform.violations, [Violation::NoLatch {frame: 0}]);
+        assert_eq!(waveform.frames[0].pixels, [0xff_ff_ff_00]);
+    }
+}
@@ -0,0 +1,112 @@
+//! The WS2812B PIO program and its timing
+//!
+//! Each `u32` pushed to the state machine becomes one pixel. For a WS2812B the
+//! color goes in the top 24 bits in GRB order, so a pixel looks like
+//! `0xGGRRBB00`. RGBW strips like the SK6812 use all 32 bits instead, which is
+//! picked by the pull threshold at install time. Bits are shifted out MSB first
+//! with autopull, and the line is held low whenever the FIFO runs dry. See
+//! `ColorOrder::pack` for building these words.
+//!
+//! The same program drives every chipset in `Chipset`, only the delays and
+//! clock divisor change.
+
+use ::pio::{Assembler, JmpCondition, OutDestination, Program, SideSet};
+use crate::hal::pio::{Buffers, PIOExt, ShiftDirection};
+
+use crate::chipset::ProgramTiming;
+use crate::clock_divisor::ClockDivisor;
+use crate::pio::{self, Pio, ProgramId, RxTx, Select, StateMachineHandle};
+use crate::state_machine::StateMachineConfig;
+
+/// Number of PIO cycles it takes to send a single bit with `program`
+pub const CYCLES_PER_BIT: u32 = 10;
+
+/// Assembles the WS2812B program from ws2812b.pio
+///
+/// The delays are fixed at 10 cycles per bit, so this needs an 8MHz PIO clock
+/// for a WS2812B.
+pub fn program() -> Program<32> {
+    let program = pio_proc::pio_file!("src/ws2812b.pio", select_program("ws2812b"));
+
+    program.program
+}
+
+/// Assembles the same program as ws2812b.pio with custom delays
+pub fn program_with_timing(timing: &ProgramTiming) -> Program<32> {
+    let (t1, t2, t3) = (timing.t1(), timing.t2(), timing.t3());
+
+    let mut a = Assembler::<32>::new_with_side_set(SideSet::new(false, 1, false));
+    let mut wrap_target = a.label();
+    let mut wrap_source = a.label();
+    let mut bitloop = a.label();
+    let mut do_zero = a.label();
+
+    a.bind(&mut wrap_target);
+    a.bind(&mut bitloop);
+    a.out_with_delay_and_side_set(OutDestination::X, 1, t3 - 1, 0);
+    a.jmp_with_delay_and_side_set(JmpCondition::XIsZero, &mut do_zero, t1 - 1, 1);
+    a.jmp_with_delay_and_side_set(JmpCondition::Always, &mut bitloop, t2 - 1, 1);
+    a.bind(&mut do_zero);
+    a.nop_with_delay_and_side_set(t2 - 1, 0);
+    a.bind(&mut wrap_source);
+
+    a.assemble_with_wrap(wrap_source, wrap_target)
+}
+
+/// State machine config for a single strip
+///
+/// The pin is driven by side-set and switched to an output before the state
+/// machine starts. The output shift register is set up to autopull one pixel
+/// at a time from a TX only FIFO.
+///
+/// * `pin` - GPIO number of the data line
+/// * `pull_threshold` - Bits per pixel, 24 for RGB strips like the WS2812B
+///   or 32 for RGBW strips like the SK6812
+pub fn config(pin: u8, clock_divisor: ClockDivisor, pull_threshold: u8) -> StateMachineConfig {
+    StateMachineConfig {
+        side_set_base: Some(pin),
+        output_pins: Some((pin, 1)),
+        autopull: true,
+        pull_threshold,
+        out_shift_direction: ShiftDirection::Left,
+        buffers: Buffers::OnlyTx,
+        ..StateMachineConfig::new(clock_divisor)
+    }
+}
+
+/// Claims a state machine and programs it to drive a single strip
+///
+/// The program is assembled with the given timing, and shares instruction
+/// memory with any other strip on the block using the same delays. The state
+/// machine is left stopped. Once it's released, the returned id has to be
+/// given to `unload_program`.
+///
+/// * `pin` - GPIO number of the data line
+/// * `pull_threshold` - Bits per pixel, 24 for RGB or 32 for RGBW
+pub fn install<P: PIOExt>(
+    pio: &mut Pio<P>,
+    timing: &ProgramTiming,
+    pin: u8,
+    pull_threshold: u8,
+) -> Result<(StateMachineHandle, ProgramId, RxTx<P>), pio::Error> {
+    let handle = pio.claim(Select::Any)?;
+
+    let id = match pio.load_program(&program_with_timing(timing)) {
+        Ok(id) => id,
+        Err(error) => {
+            pio.release(handle, None)?;
+            return Err(error)
+        },
+    };
+
+    match pio.program(&handle, id, &config(pin, timing.clock_divisor(), pull_threshold)) {
+        Ok(rxtx) => Ok((handle, id, rxtx)),
+        Err(error) => {
+            // Only gives back this load, another strip might still be using
+            // the program
+            let _ = pio.unload_program(id);
+            pio.release(handle, None)?;
+            Err(error)
+        },
+    }
+}